@@ -1,21 +1,49 @@
-use bytes::Bytes;
-use clap::Parser;
+use aho_corasick::AhoCorasick;
+use base64::Engine as _;
+use bytes::{Bytes, BytesMut};
+use chrono::Local;
+use clap::{ArgAction, Parser, ValueEnum};
 use colored::Colorize;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use once_cell::sync::Lazy;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use regex::Regex;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
-use reqwest::{Client, Method, Proxy, StatusCode, Url, Version};
-use std::io::{self};
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::Duration;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING,
+    CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION, PROXY_AUTHORIZATION, RETRY_AFTER,
+    SET_COOKIE, TRANSFER_ENCODING, USER_AGENT, WWW_AUTHENTICATE,
+};
+use reqwest::{Client, ClientBuilder, Method, Proxy, Request, StatusCode, Url, Version};
+use serde_json::json;
+use similar::TextDiff;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error as _;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::fs as tokio_fs;
-use tokio::io::{self as tokio_io, AsyncBufReadExt};
-use tokio::sync::Semaphore;
+use tokio::io::{self as tokio_io, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 use tokio::time::sleep;
-use xxhash_rust::xxh3::Xxh3; // Import bytes::Bytes
+use x509_parser::prelude::{ASN1Time, GeneralName, X509Name};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3}; // Import bytes::Bytes
 
 /// Command-line arguments structure using `clap`
 #[derive(Parser, Debug, Clone)]
@@ -25,18 +53,223 @@ use xxhash_rust::xxh3::Xxh3; // Import bytes::Bytes
     version = "1.0"
 )]
 struct Opts {
-    /// Request body
+    /// Maximum number of requests in flight at once
+    #[arg(short = 'c', long, default_value_t = 100)]
+    concurrency: usize,
+
+    /// Request each URL <n> times
+    #[arg(long = "repeat", default_value = "1")]
+    repeat: NonZeroUsize,
+
+    /// Request body; {url}, {host} and {random} are substituted per request
     #[arg(short = 'b', long)]
     body: Option<String>,
 
+    /// Gzip the request body and send it with Content-Encoding: gzip
+    #[arg(long = "compress-request")]
+    compress_request: bool,
+
+    /// Read the request body from stdin; URLs are then read from --input
+    #[arg(long = "body-from-stdin", requires = "input", conflicts_with = "body")]
+    body_from_stdin: bool,
+
+    /// A --body-from-stdin body that isn't UTF-8, sent as read without substituting tokens
+    #[arg(skip)]
+    raw_body: Option<Bytes>,
+
+    /// Stream the request body from <file> with chunked encoding instead of reading it into memory
+    #[arg(
+        long = "body-file",
+        conflicts_with_all = ["body", "body_from_stdin"]
+    )]
+    body_file: Option<PathBuf>,
+
+    /// Maximum number of input lines read ahead of the requests being sent
+    #[arg(long = "queue-size", default_value = "1024")]
+    queue_size: NonZeroUsize,
+
+    /// Print results in input order, holding back each one until those before it are done
+    #[arg(long = "ordered")]
+    ordered: bool,
+
     /// Delay between issuing requests (ms)
     #[arg(short = 'd', long, default_value_t = 100)]
     delay: u64,
 
+    /// Start at most <n> requests per second, on a steady schedule
+    #[arg(long = "rate", value_parser = parse_rate)]
+    rate: Option<f64>,
+
+    /// Vary each --rate interval randomly by up to ±<percent>
+    #[arg(long = "jitter", requires = "rate", value_parser = clap::value_parser!(u8).range(0..=100))]
+    jitter: Option<u8>,
+
+    /// Ignore the first <n> input lines, e.g. to resume an interrupted run
+    #[arg(long = "skip-lines", default_value_t = 0)]
+    skip_lines: usize,
+
+    /// Skip input lines starting with '#' as comments
+    #[arg(
+        long = "allow-comments",
+        default_value_t = true,
+        num_args = 0..=1,
+        default_missing_value = "true",
+        action = ArgAction::Set
+    )]
+    allow_comments: bool,
+
+    /// Only request input lines matching <regex> (can be specified multiple times)
+    #[arg(long = "include-regex")]
+    include_regex: Vec<Regex>,
+
+    /// Skip input lines matching <regex>, e.g. '\.pdf$' (can be specified multiple times)
+    #[arg(long = "exclude-regex")]
+    exclude_regex: Vec<Regex>,
+
+    /// Only process input lines in shard <k>/<n>, e.g. 1/4, to split a list across runs
+    #[arg(long = "shard")]
+    shard: Option<Shard>,
+
+    /// Stop after requesting <n> URLs
+    #[arg(long = "limit")]
+    limit: Option<usize>,
+
+    /// Stop the run, exiting with an error, once more than <n> requests have failed
+    #[arg(long = "max-errors")]
+    max_errors: Option<usize>,
+
+    /// Show progress on stderr, with an ETA when reading URLs from --input
+    #[arg(long = "progress")]
+    progress: bool,
+
+    /// Stop the whole run after <seconds>, cancelling requests still in flight
+    #[arg(long = "max-time")]
+    max_time: Option<u64>,
+
+    /// Maximum number of requests in flight to any one host
+    #[arg(long = "per-host-concurrency")]
+    per_host_concurrency: Option<NonZeroUsize>,
+
+    /// Send requests in batches of <n>, waiting for each batch to finish
+    #[arg(long = "batch-size")]
+    batch_size: Option<NonZeroUsize>,
+
+    /// Pause between batches (ms)
+    #[arg(long = "batch-pause", default_value_t = 0)]
+    batch_pause: u64,
+
+    /// Fetch <url>, a page that shouldn't exist, and drop responses with an identical body
+    #[arg(long = "baseline")]
+    baseline: Option<String>,
+
+    /// Also drop responses at least this similar to the --baseline body, from 0.0 to 1.0, e.g. 0.95
+    #[arg(long = "similarity", requires = "baseline", value_parser = parse_ratio)]
+    similarity: Option<f32>,
+
+    /// Abort before reading stdin unless <url> answers with a 2xx
+    #[arg(long = "preflight")]
+    preflight: Option<String>,
+
     /// Add a header to the request (can be specified multiple times)
     #[arg(short = 'H', long)]
     header: Vec<String>,
 
+    /// Add a header with a random UUID to each request, e.g. X-Request-Id
+    #[arg(long = "trace-header")]
+    trace_header: Option<HeaderName>,
+
+    /// Write "url -> trace id" lines to <file> instead of stderr
+    #[arg(long = "trace-log", requires = "trace_header")]
+    trace_log: Option<PathBuf>,
+
+    /// Log the value of the given response header for each URL, e.g. an echoed X-Trace-Id
+    #[arg(long = "log-response-header")]
+    log_response_header: Option<HeaderName>,
+
+    /// Write "url -> value" lines for --log-response-header to <file> instead of stderr
+    #[arg(long = "response-header-log", requires = "log_response_header")]
+    response_header_log: Option<PathBuf>,
+
+    /// Mark responses that took longer than <ms> to arrive with [SLOW <n>ms]
+    #[arg(long = "slow-threshold")]
+    slow_threshold: Option<u64>,
+
+    /// Write "url elapsed_ms" lines for slow responses to <file>
+    #[arg(long = "slow-log", requires = "slow_threshold")]
+    slow_log: Option<PathBuf>,
+
+    /// Write a url,status,dns_ms,connect_ms,ttfb_ms,total_ms row per response to <file>; DNS and connect times are left empty, as the client doesn't expose them
+    #[arg(long = "timing-csv")]
+    timing_csv: Option<PathBuf>,
+
+    /// Send the -H headers in a random order on each request
+    #[arg(long = "shuffle-headers")]
+    shuffle_headers: bool,
+
+    /// Seed for --shuffle-headers, for a reproducible sequence of orders
+    #[arg(long = "shuffle-seed", requires = "shuffle_headers")]
+    shuffle_seed: Option<u64>,
+
+    /// Read URLs from <file> instead of stdin
+    #[arg(short = 'i', long = "input")]
+    input: Option<PathBuf>,
+
+    /// Record each request sent (method, URL, headers and body) to <file> as JSON lines
+    #[arg(long = "manifest")]
+    manifest: Option<PathBuf>,
+
+    /// Send the requests recorded by --manifest in <file> again, exactly as recorded
+    #[arg(
+        long = "from-manifest",
+        conflicts_with_all = [
+            "input", "method", "methods_file", "both_methods", "body", "body_from_stdin",
+            "body_file", "header", "trace_header", "compress_request"
+        ]
+    )]
+    from_manifest: Option<PathBuf>,
+
+    /// Send the raw HTTP request in <file> for each input line, with {FUZZ} replaced by the line
+    #[arg(
+        long = "request-file",
+        conflicts_with_all = [
+            "from_manifest", "method", "methods_file", "both_methods", "body", "body_from_stdin",
+            "body_file", "compress_request"
+        ]
+    )]
+    request_file: Option<PathBuf>,
+
+    /// Scheme to send a --request-file request with a relative target to its Host header over
+    #[arg(long = "raw-scheme", value_enum, default_value_t = RawScheme::Https)]
+    raw_scheme: RawScheme,
+
+    /// The raw request read from --request-file
+    #[arg(skip)]
+    request_template: Option<String>,
+
+    /// Resolve input lines against <url>, so relative paths like /login can be requested
+    #[arg(long = "base")]
+    base: Option<Url>,
+
+    /// Also request the https:// variant of each http:// input URL
+    #[arg(long = "https-upgrade")]
+    https_upgrade: bool,
+
+    /// Also request each URL with the letters of its path randomly recased, reporting when the status differs
+    #[arg(long = "case-variant")]
+    case_variant: bool,
+
+    /// Sort each input URL's query parameters by name, requesting URLs that only differ in their order once
+    #[arg(long = "sort-query")]
+    sort_query: bool,
+
+    /// Request only the first input URL for each scheme, host and path, ignoring query strings and fragments
+    #[arg(long = "dedupe-path")]
+    dedupe_path: bool,
+
+    /// Skip input URLs whose scheme isn't http or https instead of trying to request them
+    #[arg(long = "strict-url")]
+    strict_url: bool,
+
     /// Don't save HTML files; useful when looking for non-HTML files only
     #[arg(long = "ignore-html")]
     ignore_html: bool,
@@ -45,22 +278,257 @@ struct Opts {
     #[arg(long = "ignore-empty")]
     ignore_empty: bool,
 
+    /// Retry a request whose body is empty or only whitespace, up to --retries times
+    #[arg(long = "retry-empty")]
+    retry_empty: bool,
+
+    /// Retry a request whose connection was closed or reset before a complete response, up to --retries times
+    #[arg(long = "retry-connection-reset", alias = "retry-on-connection-close")]
+    retry_connection_reset: bool,
+
+    /// Maximum number of times a request is retried
+    #[arg(long = "retries", default_value_t = 2)]
+    retries: usize,
+
+    /// Compare JSON bodies by content, ignoring key order and formatting, for --dedupe-body, --diff and --baseline
+    #[arg(long = "canonical-json")]
+    canonical_json: bool,
+
+    /// Don't save a body identical to one already saved in this run
+    #[arg(long = "dedupe-body")]
+    dedupe_body: bool,
+
+    /// With --dedupe-body, symlink duplicates to the first copy instead of skipping them (Unix only)
+    #[arg(long = "link-dupes", requires = "dedupe_body")]
+    link_dupes: bool,
+
+    /// Save only the .headers file for empty or whitespace-only responses
+    #[arg(long = "headers-only-when-empty", conflicts_with = "ignore_empty")]
+    headers_only_when_empty: bool,
+
+    /// Drop each response after its headers without downloading the body, saving only the .headers file
+    #[arg(
+        long = "drop-body",
+        conflicts_with_all = [
+            "retry_empty", "ignore_html", "ignore_empty", "dedupe_body", "baseline", "diff",
+            "match", "match_file", "json_path", "decode_charset", "save_bytes", "body_hash_in_headers"
+        ]
+    )]
+    drop_body: bool,
+
+    /// Maximum size of HTTP/2 response headers in bytes; HTTP/1 responses are always limited to 100 headers
+    #[arg(long = "max-header-size")]
+    max_header_size: Option<u32>,
+
+    /// Stop requesting a host once it has answered <status> <count> times, e.g. 503:5 (can be specified multiple times)
+    #[arg(long = "circuit-break")]
+    circuit_break: Vec<CircuitBreak>,
+
+    /// Back off from hosts that answer 429, retrying with a growing per-host delay
+    #[arg(long = "adaptive-delay")]
+    adaptive_delay: bool,
+
+    /// Mimic a browser's User-Agent, default headers, TLS versions and ALPN (best-effort)
+    #[arg(long = "impersonate", value_enum)]
+    impersonate: Option<Browser>,
+
+    /// Don't send Accept-Encoding or other optional client headers; Host, Content-Length
+    /// and Accept: */* (unless overridden with -H) are always sent
+    #[arg(long = "no-default-headers")]
+    no_default_headers: bool,
+
+    /// Send Accept-Encoding: <value> instead of the client's own; an empty value sends none
+    #[arg(long = "accept-encoding")]
+    accept_encoding: Option<HeaderValue>,
+
+    /// Save compressed bodies as received instead of decompressing them
+    #[arg(long = "no-auto-decompress")]
+    no_auto_decompress: bool,
+
+    /// Record each redirect hop's status and Location in the saved output
+    #[arg(long = "record-redirects")]
+    record_redirects: bool,
+
     /// Use HTTP Keep-Alive
     #[arg(short = 'k', long = "keep-alive", alias = "keep-alives")]
     keep_alive: bool,
 
+    /// Maximum number of idle connections kept open to each host
+    #[arg(long = "pool-max-idle-per-host", requires = "keep_alive")]
+    pool_max_idle_per_host: Option<usize>,
+
+    /// Send a HEAD and then a GET for each URL, saving the HEAD's headers separately
+    #[arg(
+        long = "both-methods",
+        conflicts_with_all = ["method", "body", "body_from_stdin", "body_file"]
+    )]
+    both_methods: bool,
+
     /// HTTP method to use (default: GET, or POST if body is specified)
     #[arg(short = 'm', long, default_value = "GET")]
     method: String,
 
+    /// Send a request with each method listed in <file>, one per line, for each URL
+    #[arg(long = "methods-file", conflicts_with_all = ["method", "both_methods"])]
+    methods_file: Option<PathBuf>,
+
+    /// Methods read from --methods-file
+    #[arg(skip)]
+    methods: Vec<Method>,
+
     /// Save responses that include <string> in the body
     #[arg(short = 'M', long)]
     r#match: Option<String>,
 
+    /// Save responses that include any of the strings in <file>, one per line (with -M, that string too)
+    #[arg(long = "match-file")]
+    match_file: Option<PathBuf>,
+
+    /// With --match-file, save only responses that include every string
+    #[arg(long = "match-all", requires = "match_file")]
+    match_all: bool,
+
+    /// Save JSON responses that have a value at <path>, e.g. $.data.items[0].id (with -M or --match-file, that too)
+    #[arg(long = "json-path")]
+    json_path: Option<JsonPath>,
+
+    /// With --json-path, save only when the value equals <value>, given as JSON or a bare string
+    #[arg(long = "json-value", requires = "json_path")]
+    json_value: Option<String>,
+
+    /// Write the URL of each response matching -M, --match-file or --json-path to <file>, saving bodies only with -S or -s
+    #[arg(long = "match-report")]
+    match_report: Option<PathBuf>,
+
     /// Directory to save responses in (will be created)
     #[arg(short = 'o', long, default_value = "out")]
     output: PathBuf,
 
+    /// Longest directory name, in bytes, a URL path segment is saved under; longer ones are cut and hashed
+    #[arg(long = "max-name-len", default_value_t = 255, value_parser = clap::value_parser!(u16).range(32..))]
+    max_name_len: u16,
+
+    /// How to name per-host output directories
+    #[arg(long = "host-dir", value_enum, default_value_t = HostDir::Host)]
+    host_dir: HostDir,
+
+    /// Name host directories with the host as written in the input instead of lowercased
+    #[arg(long = "preserve-host-case")]
+    preserve_host_case: bool,
+
+    /// What to do when a different request in this run maps to a file name already used
+    #[arg(long = "on-collision", value_enum, default_value_t = Collision::Overwrite)]
+    on_collision: Collision,
+
+    /// Save files directly in the output directory, listing hash, status and URL in manifest.tsv
+    #[arg(long = "flat")]
+    flat: bool,
+
+    /// Save responses under a directory for the negotiated HTTP version, e.g. http1.1 or http2
+    #[arg(long = "http-version-dir", conflicts_with = "flat")]
+    http_version_dir: bool,
+
+    /// Save responses under a directory for their detected type, e.g. out/images/<host>/... or out/json/<host>/...
+    #[arg(long = "bucket-by-type", conflicts_with = "flat")]
+    bucket_by_type: bool,
+
+    /// Append every saved response to a single <host>.txt file per host
+    #[arg(long = "per-host-file", conflicts_with_all = ["flat", "link_dupes"])]
+    per_host_file: bool,
+
+    /// Save responses under a dated directory in the output directory
+    #[arg(long = "date-dir")]
+    date_dir: bool,
+
+    /// Format of the --date-dir directory name (strftime syntax)
+    #[arg(long = "date-format", default_value = "%Y-%m-%d")]
+    date_format: String,
+
+    /// Don't save responses whose Content-Length header is below <bytes>
+    #[arg(long = "min-content-length")]
+    min_content_length: Option<u64>,
+
+    /// Don't save responses whose Content-Length header is above <bytes>
+    #[arg(long = "max-content-length")]
+    max_content_length: Option<u64>,
+
+    /// Don't save responses without a Content-Length header when filtering on it
+    #[arg(long = "exclude-unknown-length")]
+    exclude_unknown_length: bool,
+
+    /// Set the mode of saved files, e.g. 600 (Unix only)
+    #[arg(long = "chmod", value_parser = parse_mode)]
+    chmod: Option<u32>,
+
+    /// Flush each saved file to disk before moving on, so a crash can't lose it
+    #[arg(long = "fsync")]
+    fsync: bool,
+
+    /// Write every distinct match of <regex> in each body to --extract-output
+    #[arg(long = "extract", requires = "extract_output")]
+    extract: Option<regex::bytes::Regex>,
+
+    /// File --extract writes "url<TAB>match" lines to
+    #[arg(long = "extract-output", requires = "extract")]
+    extract_output: Option<PathBuf>,
+
+    /// Name saved bodies with an extension matching their Content-Type or contents, e.g. .json, instead of .body
+    #[arg(long = "detect-extension")]
+    detect_extension: bool,
+
+    /// Save the visible text of HTML bodies to a .txt file alongside the .body
+    #[arg(long = "extract-text")]
+    extract_text: bool,
+
+    /// Leave the given response header out of saved files (can be specified multiple times)
+    #[arg(long = "strip-header")]
+    strip_header: Vec<HeaderName>,
+
+    /// Save only the given response headers (can be specified multiple times)
+    #[arg(long = "keep-header")]
+    keep_header: Vec<HeaderName>,
+
+    /// Save only the first <n> bytes of each body, noting the full length in the .headers file
+    #[arg(long = "save-bytes")]
+    save_bytes: Option<usize>,
+
+    /// Read at most <n> bytes of each response body, dropping the rest
+    #[arg(long = "max-body-size")]
+    max_body_size: Option<usize>,
+
+    /// Transcode bodies to UTF-8 from the charset in their Content-Type or BOM before matching and saving
+    #[arg(long = "decode-charset")]
+    decode_charset: bool,
+
+    /// Add a synthetic X-FFF-Body-XXH3 line with the saved body's hash to saved .headers files
+    #[arg(long = "body-hash-in-headers")]
+    body_hash_in_headers: bool,
+
+    /// Save the server's TLS certificate (subject, issuer, validity, SANs and PEM) to a .cert file
+    #[arg(long = "save-cert")]
+    save_cert: bool,
+
+    /// Save a .json file of request and response metadata alongside each body
+    #[arg(long = "meta-json")]
+    meta_json: bool,
+
+    /// Run a shell command for each saved response; {file}, {url} and {status} are substituted
+    #[arg(long = "exec")]
+    exec: Option<String>,
+
+    /// Maximum number of --exec commands running at once
+    #[arg(long = "exec-concurrency", default_value_t = 4)]
+    exec_concurrency: usize,
+
+    /// Only report and save responses whose body differs from the capture in <dir>; a --body with
+    /// {random} names each capture anew, so those are never compared
+    #[arg(long = "diff")]
+    diff: Option<PathBuf>,
+
+    /// Print a unified diff of changed text bodies in --diff mode
+    #[arg(long = "show-diff")]
+    show_diff: bool,
+
     /// Save responses with given status code (can be specified multiple times)
     #[arg(short = 's', long = "save-status")]
     save_status: Vec<u16>,
@@ -69,260 +537,4479 @@ struct Opts {
     #[arg(short = 'S', long = "save")]
     save: bool,
 
+    /// Never save responses with given status code or range, e.g. 404 or 500-599 (can be specified multiple times)
+    #[arg(long = "skip-status")]
+    skip_status: Vec<StatusRange>,
+
+    /// Report a failure and exit nonzero if a status is outside <status> or range, e.g. 200 or 200-299 (can be specified multiple times)
+    #[arg(long = "expect")]
+    expect: Vec<StatusRange>,
+
+    /// Print one JSON object per line for each result, and for each error on stderr
+    #[arg(long = "json")]
+    json: bool,
+
+    /// When to color output; auto colors only when stdout is a terminal and NO_COLOR is unset
+    #[arg(long = "color", value_enum, default_value = "auto")]
+    color: Color,
+
+    /// Write every result to <file> as a single JSON array when the run ends
+    #[arg(long = "json-file")]
+    json_file: Option<PathBuf>,
+
+    /// Put each kept response's headers and base64 body on its JSON line instead of saving files (implies --json)
+    #[arg(long = "jsonl-full", conflicts_with_all = ["exec", "report", "dedupe_body"])]
+    jsonl_full: bool,
+
+    /// Write an index.html to the output directory linking every saved response when the run ends
+    #[arg(long = "report")]
+    report: bool,
+
+    /// Print the HTML <title> of each response
+    #[arg(long = "titles")]
+    titles: bool,
+
+    /// Flag responses that look like they came from a WAF, e.g. [WAF: cloudflare]
+    #[arg(long = "detect-waf")]
+    detect_waf: bool,
+
+    /// Print the value of the given response header on each line (can be specified multiple times)
+    #[arg(long = "show-header")]
+    show_header: Vec<HeaderName>,
+
+    /// Save the request as sent, including client-added headers, to a .request file
+    #[arg(long = "save-request")]
+    save_request: bool,
+
+    /// Only follow redirects that stay on the original host; off-host redirects are returned as-is
+    #[arg(long = "same-host-redirects")]
+    same_host_redirects: bool,
+
+    /// Follow HTML <meta http-equiv="refresh"> redirects
+    #[arg(long = "meta-refresh")]
+    meta_refresh: bool,
+
+    /// Also fetch the entries of Apache/nginx-style directory listings
+    #[arg(long = "recursive")]
+    recursive: bool,
+
+    /// With --recursive, how many listings deep to follow entries
+    #[arg(long = "max-depth", default_value_t = 10, requires = "recursive")]
+    max_depth: usize,
+
+    /// Write each Set-Cookie header and its security flags to <file>
+    #[arg(long = "cookies-report")]
+    cookies_report: Option<PathBuf>,
+
+    /// Send requests through the network interface <name>, e.g. eth0 (Linux only)
+    #[arg(long = "interface")]
+    interface: Option<String>,
+
+    /// Resolve host names with the DNS-over-HTTPS JSON API at <url>, e.g. https://cloudflare-dns.com/dns-query
+    #[arg(long = "doh")]
+    doh: Option<Url>,
+
+    /// Connect to <addr> for <host> instead of looking it up, e.g. example.com:203.0.113.10 (can be specified multiple times)
+    #[arg(long = "resolve")]
+    resolve: Vec<ResolveOverride>,
+
+    /// Send <value> as the Host header on every request, replacing any other, e.g. for virtual host discovery
+    #[arg(long = "host")]
+    host: Option<HeaderValue>,
+
+    /// Send <name> as the TLS server name (SNI) for https URLs, keeping the URL's host in the Host header
+    #[arg(long = "sni", conflicts_with_all = ["proxy", "proxy_file", "proxy_map", "doh"])]
+    sni: Option<String>,
+
+    /// Verify TLS certificate chains but accept any host name on them (certificates are otherwise not verified)
+    #[arg(long = "accept-invalid-hostnames")]
+    accept_invalid_hostnames: bool,
+
+    /// With --accept-invalid-hostnames, also trust the CA certificates in <file> (PEM)
+    #[arg(long = "cacert", requires = "accept_invalid_hostnames")]
+    cacert: Option<PathBuf>,
+
+    /// Certificates read from --cacert
+    #[arg(skip)]
+    root_certs: Vec<reqwest::Certificate>,
+
     /// Use the provided HTTP proxy
     #[arg(short = 'x', long = "proxy")]
     proxy: Option<String>,
+
+    /// Spread requests round-robin across the proxies listed in <file>, one per line
+    #[arg(long = "proxy-file")]
+    proxy_file: Option<PathBuf>,
+
+    /// Send requests for the hosts in <file> through their own proxy, one "host proxy-url" per line; other hosts use -x, --proxy-file or no proxy
+    #[arg(long = "proxy-map")]
+    proxy_map: Option<PathBuf>,
+}
+
+/// Browsers that `--impersonate` can mimic.
+///
+/// rustls doesn't allow reordering cipher suites or TLS extensions, so the
+/// JA3 fingerprint stays rustls'; only the ALPN list (h2, http/1.1), the TLS
+/// version range and the HTTP-level headers are brought in line.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Browser {
+    Chrome,
+    Firefox,
+}
+
+impl Browser {
+    fn user_agent(self) -> &'static str {
+        match self {
+            Browser::Chrome => "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36",
+            Browser::Firefox => "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:127.0) Gecko/20100101 Firefox/127.0",
+        }
+    }
+
+    fn default_headers(self) -> HeaderMap {
+        let headers: &[(&str, &str)] = match self {
+            Browser::Chrome => &[
+                ("accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8"),
+                ("accept-language", "en-US,en;q=0.9"),
+                ("sec-ch-ua", "\"Not/A)Brand\";v=\"8\", \"Chromium\";v=\"126\", \"Google Chrome\";v=\"126\""),
+                ("sec-ch-ua-mobile", "?0"),
+                ("sec-ch-ua-platform", "\"Windows\""),
+                ("upgrade-insecure-requests", "1"),
+            ],
+            Browser::Firefox => &[
+                ("accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8"),
+                ("accept-language", "en-US,en;q=0.5"),
+                ("upgrade-insecure-requests", "1"),
+            ],
+        };
+
+        headers
+            .iter()
+            .map(|&(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v)))
+            .collect()
+    }
+}
+
+/// How the per-host output directory is named
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum HostDir {
+    /// The bare host name, as returned by the URL
+    Host,
+    /// The host and port, e.g. example.com_8080, with IPv6 colons replaced
+    HostPort,
+}
+
+/// When to color terminal output
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    fn enabled(self) -> bool {
+        match self {
+            Color::Auto => io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
+
+/// How to save a response whose file name was already used by a different request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Collision {
+    /// Replace the earlier files
+    Overwrite,
+    /// Add -1, -2, ... to the file name
+    Suffix,
+    /// Keep the earlier files and don't save this response
+    Skip,
+}
+
+/// Scheme of --request-file requests with a relative target and no --base
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RawScheme {
+    Http,
+    Https,
+}
+
+/// Inclusive range of HTTP status codes, parsed from `404` or `500-599`
+#[derive(Debug, Clone, Copy)]
+struct StatusRange {
+    start: u16,
+    end: u16,
+}
+
+impl StatusRange {
+    fn contains(&self, status: u16) -> bool {
+        (self.start..=self.end).contains(&status)
+    }
+}
+
+impl fmt::Display for StatusRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for StatusRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid status code: {}", v))
+        };
+
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => (parse(s)?, parse(s)?),
+        };
+
+        if start > end {
+            return Err(format!("invalid status range: {}", s));
+        }
+
+        Ok(StatusRange { start, end })
+    }
+}
+
+/// A status and how many times a host may answer it, parsed from `status:count`
+#[derive(Debug, Clone, Copy)]
+struct CircuitBreak {
+    status: u16,
+    count: usize,
+}
+
+impl FromStr for CircuitBreak {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid circuit break: {} (expected status:count, e.g. 503:5)",
+                s
+            )
+        };
+
+        let (status, count) = s.split_once(':').ok_or_else(invalid)?;
+        let status = status.trim().parse::<u16>().map_err(|_| invalid())?;
+        let count = count.trim().parse::<usize>().map_err(|_| invalid())?;
+
+        if !(100..=999).contains(&status) || count == 0 {
+            return Err(invalid());
+        }
+
+        Ok(CircuitBreak { status, count })
+    }
+}
+
+/// A JSONPath-style location such as `$.data.items[0].id`, kept as the JSON
+/// pointer it translates to; only member and index steps are supported
+#[derive(Debug, Clone)]
+struct JsonPath {
+    pointer: String,
+}
+
+impl JsonPath {
+    /// Whether `body` is JSON with a value at the path, equal to `expected` if given.
+    /// `expected` is compared as JSON if it parses as JSON, and as a string otherwise
+    fn matches(&self, body: &[u8], expected: Option<&str>) -> bool {
+        let document = match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(document) => document,
+            Err(_) => return false,
+        };
+        let value = match document.pointer(&self.pointer) {
+            Some(value) => value,
+            None => return false,
+        };
+
+        match expected {
+            None => true,
+            Some(expected) => match serde_json::from_str::<serde_json::Value>(expected) {
+                Ok(expected) => *value == expected,
+                Err(_) => value.as_str() == Some(expected),
+            },
+        }
+    }
+}
+
+impl FromStr for JsonPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid JSON path: {} (expected e.g. $.data.items[0].id)",
+                s
+            )
+        };
+
+        let mut rest = s.strip_prefix('$').ok_or_else(invalid)?;
+        let mut pointer = String::new();
+
+        while !rest.is_empty() {
+            let step = if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                rest = &after[end..];
+                &after[..end]
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(invalid)?;
+                rest = &after[end + 1..];
+                after[..end].trim_matches(|c| c == '\'' || c == '"')
+            } else {
+                return Err(invalid());
+            };
+
+            if step.is_empty() {
+                return Err(invalid());
+            }
+            pointer.push('/');
+            pointer.push_str(&step.replace('~', "~0").replace('/', "~1"));
+        }
+
+        Ok(JsonPath { pointer })
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("invalid ratio: {} (expected 0.0 to 1.0)", s)),
+    }
+}
+
+/// One of `count` disjoint subsets of input lines, parsed from `k/n`
+#[derive(Debug, Clone, Copy)]
+struct Shard {
+    index: usize,
+    count: usize,
+}
+
+impl Shard {
+    fn includes(&self, line_index: usize) -> bool {
+        line_index % self.count == self.index
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid shard: {} (expected k/n with 1 <= k <= n)", s);
+
+        let (k, n) = s.split_once('/').ok_or_else(invalid)?;
+        let k = k.trim().parse::<usize>().map_err(|_| invalid())?;
+        let n = n.trim().parse::<usize>().map_err(|_| invalid())?;
+
+        if k == 0 || k > n {
+            return Err(invalid());
+        }
+
+        Ok(Shard {
+            index: k - 1,
+            count: n,
+        })
+    }
+}
+
+/// A pinned address for a host name, parsed from `host:addr`
+#[derive(Debug, Clone)]
+struct ResolveOverride {
+    host: String,
+    addr: IpAddr,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid resolve override: {} (expected host:addr)", s);
+
+        // Host names can't contain colons, so everything after the first is the address
+        let (host, addr) = s.split_once(':').ok_or_else(invalid)?;
+        let addr = addr.trim_start_matches('[').trim_end_matches(']');
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(ResolveOverride {
+            host: host.to_ascii_lowercase(),
+            addr,
+        })
+    }
+}
+
+/// Strings from --match-file, searched for in a single pass over each body
+struct BodyMatcher {
+    automaton: AhoCorasick,
+    all: bool,
+}
+
+impl BodyMatcher {
+    fn load(opts: &Opts, path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut patterns: Vec<&str> = contents.lines().filter(|l| !l.is_empty()).collect();
+        if let Some(ref m) = opts.r#match {
+            patterns.push(m);
+        }
+        patterns.sort_unstable();
+        patterns.dedup();
+
+        if patterns.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no strings to match in {}", path.display()),
+            ));
+        }
+
+        let automaton = AhoCorasick::new(&patterns)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        Ok(BodyMatcher {
+            automaton,
+            all: opts.match_all,
+        })
+    }
+
+    fn matches(&self, body: &[u8]) -> bool {
+        if !self.all {
+            return self.automaton.is_match(body);
+        }
+
+        let mut found = vec![false; self.automaton.patterns_len()];
+        let mut remaining = found.len();
+        for m in self.automaton.find_overlapping_iter(body) {
+            let seen = &mut found[m.pattern().as_usize()];
+            if !*seen {
+                *seen = true;
+                remaining -= 1;
+                if remaining == 0 {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// State shared between all request tasks
+struct SharedState {
+    output_dir: PathBuf,
+    cookies_report: Option<ReportWriter>,
+    manifest: Option<ReportWriter>,
+    request_manifest: Option<ReportWriter>,
+    trace_log: Option<ReportWriter>,
+    response_header_log: Option<ReportWriter>,
+    slow_log: Option<ReportWriter>,
+    timing_csv: Option<ReportWriter>,
+    extract_output: Option<ReportWriter>,
+    match_report: Option<ReportWriter>,
+    matcher: Option<BodyMatcher>,
+    baseline: Option<Baseline>,
+    saved_bodies: Mutex<HashMap<u64, PathBuf>>,
+    claimed_names: Mutex<HashMap<PathBuf, u64>>,
+    sni_clients: Mutex<HashMap<(String, u16), Client>>,
+    json_results: Option<Mutex<Vec<serde_json::Value>>>,
+    host_files: Mutex<HashMap<PathBuf, Arc<ReportWriter>>>,
+    errors: [AtomicUsize; RequestErrorKind::ALL.len()],
+    unexpected: AtomicUsize,
+    exec_slots: Semaphore,
+    throttle: Option<Throttle>,
+    header_rng: Option<Mutex<StdRng>>,
+    host_slots: Mutex<HashMap<String, Arc<Semaphore>>>,
+    per_host_concurrency: Option<NonZeroUsize>,
+    status_counts: Mutex<HashMap<(String, u16), usize>>,
+    broken_hosts: Mutex<HashSet<String>>,
+    reorder: Option<Mutex<ReorderBuffer>>,
+    links: Option<mpsc::UnboundedSender<String>>,
+    visited: Mutex<HashMap<String, usize>>,
+}
+
+impl SharedState {
+    fn new(opts: &Opts) -> io::Result<Self> {
+        let mut output_dir = opts.output.clone();
+        if opts.date_dir {
+            let mut date = String::new();
+            write!(date, "{}", Local::now().format(&opts.date_format)).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid date format: {}", opts.date_format),
+                )
+            })?;
+            output_dir.push(date);
+        }
+
+        let cookies_report = match opts.cookies_report {
+            Some(ref path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+
+        // Flat output loses the URL from the path, so keep it in a manifest
+        let manifest = if opts.flat {
+            std::fs::create_dir_all(&output_dir)?;
+            Some(ReportWriter::saved(opts, &output_dir.join("manifest.tsv"))?)
+        } else {
+            None
+        };
+
+        let trace_log = match opts.trace_log {
+            Some(ref path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+
+        let response_header_log = match opts.response_header_log {
+            Some(ref path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+
+        let slow_log = match opts.slow_log {
+            Some(ref path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+
+        let timing_csv = match opts.timing_csv {
+            Some(ref path) => {
+                let csv = ReportWriter::create(path)?;
+                csv.write_line("url,status,dns_ms,connect_ms,ttfb_ms,total_ms");
+                Some(csv)
+            }
+            None => None,
+        };
+
+        let request_manifest = match opts.manifest {
+            Some(ref path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+
+        let matcher = match opts.match_file {
+            Some(ref path) => Some(BodyMatcher::load(opts, path)?),
+            None => None,
+        };
+
+        let extract_output = match opts.extract_output {
+            Some(ref path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+
+        let match_report = match opts.match_report {
+            Some(ref path) => Some(ReportWriter::create(path)?),
+            None => None,
+        };
+
+        Ok(SharedState {
+            output_dir,
+            cookies_report,
+            manifest,
+            request_manifest,
+            trace_log,
+            response_header_log,
+            slow_log,
+            timing_csv,
+            extract_output,
+            match_report,
+            matcher,
+            baseline: None,
+            saved_bodies: Mutex::new(HashMap::new()),
+            claimed_names: Mutex::new(HashMap::new()),
+            sni_clients: Mutex::new(HashMap::new()),
+            json_results: (opts.json_file.is_some() || opts.report).then(|| Mutex::new(Vec::new())),
+            host_files: Mutex::new(HashMap::new()),
+            errors: Default::default(),
+            unexpected: AtomicUsize::new(0),
+            exec_slots: Semaphore::new(opts.exec_concurrency.max(1)),
+            throttle: opts.adaptive_delay.then(Throttle::default),
+            header_rng: opts.shuffle_headers.then(|| {
+                Mutex::new(match opts.shuffle_seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                })
+            }),
+            host_slots: Mutex::new(HashMap::new()),
+            per_host_concurrency: opts.per_host_concurrency,
+            status_counts: Mutex::new(HashMap::new()),
+            broken_hosts: Mutex::new(HashSet::new()),
+            reorder: opts.ordered.then(|| Mutex::new(ReorderBuffer::default())),
+            links: None,
+            visited: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// With --recursive, queue the entries of a directory listing to be fetched,
+    /// one level deeper than the listing itself. Every URL is queued at most
+    /// once, so links that lead back to a fetched entry don't loop
+    fn queue_listing(&self, opts: &Opts, raw_url: &str, response_data: &ResponseData) {
+        let links = match self.links {
+            Some(ref links) => links,
+            None => return,
+        };
+        if !response_data.status.is_success() || !is_directory_listing(&response_data.response_body)
+        {
+            return;
+        }
+
+        // URLs from the input are depth 0 and only known once they're listings.
+        // A listing reached by a redirect is known by where it ended up too, so
+        // links back to it aren't followed
+        let mut visited = self.visited.lock().unwrap();
+        let listing = input_url(opts, raw_url).map_or_else(|_| raw_url.to_string(), String::from);
+        let depth = *visited.entry(listing).or_insert(0);
+        visited
+            .entry(response_data.resp_url.to_string())
+            .or_insert(depth);
+        if depth >= opts.max_depth {
+            return;
+        }
+        for child in listing_entries(&response_data.resp_url, &response_data.response_body) {
+            // Keyed as the dispatcher will send it, so the depth is found again
+            let child = if opts.sort_query {
+                sort_query(opts, child.as_str())
+            } else {
+                child.to_string()
+            };
+            if let Entry::Vacant(entry) = visited.entry(child) {
+                let _ = links.send(entry.key().clone());
+                entry.insert(depth + 1);
+            }
+        }
+    }
+
+    /// Wait for a free slot for the URL's host, if per-host concurrency is capped
+    async fn acquire_host_slot(&self, url: &Url) -> Option<OwnedSemaphorePermit> {
+        let limit = self.per_host_concurrency?;
+        let host = url.host_str().unwrap_or_default().to_string();
+
+        let slots = {
+            let mut hosts = self.host_slots.lock().unwrap();
+            Arc::clone(
+                hosts
+                    .entry(host)
+                    .or_insert_with(|| Arc::new(Semaphore::new(limit.get()))),
+            )
+        };
+
+        slots.acquire_owned().await.ok()
+    }
+
+    /// Whether --circuit-break has stopped requests to the URL's host
+    fn is_broken(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        self.broken_hosts.lock().unwrap().contains(host)
+    }
+
+    /// Count a host's status towards --circuit-break, returning the threshold
+    /// it reached if this response stopped requests to the host
+    fn record_status(&self, opts: &Opts, url: &Url, status: StatusCode) -> Option<CircuitBreak> {
+        let status = status.as_u16();
+        let breaker = opts.circuit_break.iter().find(|b| b.status == status)?;
+        let host = url.host_str().unwrap_or_default().to_string();
+
+        let count = {
+            let mut counts = self.status_counts.lock().unwrap();
+            let count = counts.entry((host.clone(), status)).or_insert(0);
+            *count += 1;
+            *count
+        };
+
+        if count >= breaker.count && self.broken_hosts.lock().unwrap().insert(host) {
+            Some(*breaker)
+        } else {
+            None
+        }
+    }
+
+    /// Claim `path` as the saved copy of `body`, or return the path of an
+    /// identical body saved earlier in the run
+    fn claim_body(&self, body: &[u8], path: &Path) -> Option<PathBuf> {
+        let mut saved = self.saved_bodies.lock().unwrap();
+        match saved.entry(xxh3_64(body)) {
+            Entry::Occupied(e) => Some(e.get().clone()),
+            Entry::Vacant(e) => {
+                e.insert(path.to_path_buf());
+                None
+            }
+        }
+    }
+
+    /// Claim the file name `base` for the request identified by `request`,
+    /// returning the suffix to save it under, or None if --on-collision skip
+    /// finds it taken by a different request
+    fn claim_name(&self, opts: &Opts, base: &Path, request: u64) -> Option<String> {
+        let mut names = self.claimed_names.lock().unwrap();
+        let mut suffix = String::new();
+
+        for n in 1.. {
+            let candidate = PathBuf::from(format!("{}{}", base.display(), suffix));
+            match names.get(&candidate) {
+                Some(&owner) if owner != request => match opts.on_collision {
+                    Collision::Overwrite => break,
+                    Collision::Skip => return None,
+                    Collision::Suffix => suffix = format!("-{}", n),
+                },
+                Some(_) => break,
+                None => {
+                    names.insert(candidate, request);
+                    break;
+                }
+            }
+        }
+
+        Some(suffix)
+    }
+
+    /// Append a response to its host's --per-host-file, returning the file's path
+    fn append_to_host_file(
+        &self,
+        opts: &Opts,
+        response_data: &ResponseData,
+    ) -> io::Result<PathBuf> {
+        let path = self
+            .output_dir
+            .join(format!("{}.txt", host_dir(opts, response_data)));
+
+        let writer = {
+            let mut files = self.host_files.lock().unwrap();
+            match files.get(&path) {
+                Some(writer) => Arc::clone(writer),
+                None => {
+                    std::fs::create_dir_all(&self.output_dir)?;
+                    let writer = Arc::new(ReportWriter::saved(opts, &path)?);
+                    files.insert(path.clone(), Arc::clone(&writer));
+                    writer
+                }
+            }
+        };
+
+        let mut record = format!(
+            "==== {} {} ({}) ====\n",
+            response_data.method,
+            response_data.raw_url,
+            response_data.status.as_u16()
+        )
+        .into_bytes();
+        for (k, v) in saved_headers(opts, &response_data.resp_headers).iter() {
+            record.extend_from_slice(format!("< {}: ", k).as_bytes());
+            record.extend_from_slice(v.as_bytes());
+            record.push(b'\n');
+        }
+        record.push(b'\n');
+        record.extend_from_slice(&response_data.response_body);
+        record.extend_from_slice(b"\n\n");
+
+        writer.write_record(&record)?;
+        Ok(path)
+    }
+
+    fn record_error(&self, kind: RequestErrorKind) {
+        self.errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of requests that failed so far, of any kind
+    fn error_count(&self) -> usize {
+        self.errors.iter().map(|n| n.load(Ordering::Relaxed)).sum()
+    }
+
+    /// One-line count of failed requests by kind, if any failed
+    fn error_summary(&self, json: bool) -> Option<String> {
+        let counts: Vec<(&str, usize)> = RequestErrorKind::ALL
+            .iter()
+            .filter_map(
+                |&kind| match self.errors[kind as usize].load(Ordering::Relaxed) {
+                    0 => None,
+                    n => Some((kind.label(), n)),
+                },
+            )
+            .collect();
+
+        if counts.is_empty() {
+            None
+        } else if json {
+            let counts: serde_json::Map<String, serde_json::Value> = counts
+                .into_iter()
+                .map(|(label, n)| (label.to_string(), n.into()))
+                .collect();
+            Some(json!({ "failed_requests": counts }).to_string())
+        } else {
+            let counts: Vec<String> = counts
+                .iter()
+                .map(|(label, n)| format!("{}: {}", label, n))
+                .collect();
+            Some(format!("Failed requests ({})", counts.join(", ")))
+        }
+    }
+}
+
+/// Delay applied to a host after its first 429
+const THROTTLE_INITIAL_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound on any host's delay
+const THROTTLE_MAX_DELAY: Duration = Duration::from_secs(30);
+/// How much a host's delay shrinks after a run of successes
+const THROTTLE_EASE_STEP: Duration = Duration::from_millis(100);
+/// Successful responses needed before a host's delay shrinks
+const THROTTLE_EASE_AFTER: u32 = 5;
+/// Times a rate-limited request is retried before its 429 is kept
+const THROTTLE_MAX_RETRIES: usize = 5;
+
+/// Per-host delays that double on each 429 and shrink gradually after successes
+#[derive(Default)]
+struct Throttle {
+    hosts: Mutex<HashMap<String, HostDelay>>,
+}
+
+#[derive(Default)]
+struct HostDelay {
+    delay: Duration,
+    successes: u32,
+}
+
+impl Throttle {
+    fn delay(&self, host: &str) -> Duration {
+        let hosts = self.hosts.lock().unwrap();
+        hosts.get(host).map_or(Duration::ZERO, |h| h.delay)
+    }
+
+    fn back_off(&self, host: &str, retry_after: Option<Duration>) -> Duration {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.delay = (state.delay * 2)
+            .max(THROTTLE_INITIAL_DELAY)
+            .max(retry_after.unwrap_or_default())
+            .min(THROTTLE_MAX_DELAY);
+        state.successes = 0;
+        state.delay
+    }
+
+    fn ease(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(state) = hosts.get_mut(host) {
+            state.successes += 1;
+            if state.successes >= THROTTLE_EASE_AFTER {
+                state.delay = state.delay.saturating_sub(THROTTLE_EASE_STEP);
+                state.successes = 0;
+            }
+        }
+    }
+}
+
+/// Maximum number of redirects followed per URL
+const MAX_REDIRECTS: usize = 10;
+
+/// A redirect response passed through on the way to the final response
+struct RedirectHop {
+    status: StatusCode,
+    location: Url,
+}
+
+/// Send a request through the throttle, if enabled, following and recording
+/// redirects by hand when --record-redirects is set
+async fn send_request(
+    client: &Client,
+    opts: &Opts,
+    shared: &SharedState,
+    request: Request,
+    redirects: &mut Vec<RedirectHop>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut request = request;
+
+    loop {
+        let template = request.try_clone();
+
+        let resp = match shared.throttle {
+            Some(ref throttle) => execute_throttled(client, opts, throttle, request).await?,
+            None => client.execute(request).await?,
+        };
+
+        if !opts.record_redirects || redirects.len() >= MAX_REDIRECTS {
+            return Ok(resp);
+        }
+
+        let (mut template, location) = match (template, redirect_target(&resp)) {
+            (Some(template), Some(location)) => (template, location),
+            _ => return Ok(resp),
+        };
+
+        if opts.same_host_redirects && location.host_str() != template.url().host_str() {
+            return Ok(resp);
+        }
+
+        // 307 and 308 repeat the request; other redirects switch to a bodyless GET
+        let status = resp.status();
+        let keep_method =
+            status == StatusCode::TEMPORARY_REDIRECT || status == StatusCode::PERMANENT_REDIRECT;
+        let method = if keep_method {
+            template.method().clone()
+        } else {
+            Method::GET
+        };
+
+        let mut headers = std::mem::take(template.headers_mut());
+
+        // Like the client's own redirect policy, credentials never leave the origin
+        if !same_origin(template.url(), &location) {
+            for name in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, WWW_AUTHENTICATE] {
+                headers.remove(name);
+            }
+        }
+        if !keep_method {
+            for name in [
+                CONTENT_TYPE,
+                CONTENT_ENCODING,
+                CONTENT_LENGTH,
+                TRANSFER_ENCODING,
+            ] {
+                headers.remove(name);
+            }
+        }
+
+        let mut next = Request::new(method, location.clone());
+        *next.headers_mut() = headers;
+        if keep_method {
+            *next.body_mut() = template.body_mut().take();
+        }
+
+        redirects.push(RedirectHop { status, location });
+        request = next;
+    }
+}
+
+/// Whether two URLs share a scheme, host and port
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str() == b.host_str()
+        && a.port_or_known_default() == b.port_or_known_default()
+}
+
+/// Where a redirect response points, resolved against its URL
+fn redirect_target(resp: &reqwest::Response) -> Option<Url> {
+    if !resp.status().is_redirection() {
+        return None;
+    }
+
+    let location = resp.headers().get(LOCATION)?.to_str().ok()?;
+    resp.url().join(location).ok()
+}
+
+/// Send a request, waiting out the host's delay and retrying while it answers 429
+async fn execute_throttled(
+    client: &Client,
+    opts: &Opts,
+    throttle: &Throttle,
+    request: Request,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let host = request.url().host_str().unwrap_or("").to_string();
+    let mut request = request;
+
+    for _ in 0..THROTTLE_MAX_RETRIES {
+        let retry = match request.try_clone() {
+            Some(r) => r,
+            None => break,
+        };
+
+        let delay = throttle.delay(&host);
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+
+        let resp = client.execute(request).await?;
+        if resp.status() != StatusCode::TOO_MANY_REQUESTS {
+            throttle.ease(&host);
+            return Ok(resp);
+        }
+
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let delay = throttle.back_off(&host, retry_after);
+        print_notice(
+            opts,
+            &host,
+            "rate_limited",
+            &format!(
+                "Rate limited by {}, delaying requests by {}ms",
+                host,
+                delay.as_millis()
+            ),
+        );
+
+        request = retry;
+    }
+
+    let delay = throttle.delay(&host);
+    if !delay.is_zero() {
+        sleep(delay).await;
+    }
+    client.execute(request).await
+}
+
+/// A report file that many tasks append whole lines to
+struct ReportWriter {
+    file: Mutex<File>,
+    fsync: bool,
+}
+
+impl ReportWriter {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(ReportWriter {
+            file: Mutex::new(File::create(path)?),
+            fsync: false,
+        })
+    }
+
+    /// Append to a file in the output directory, which holds response data
+    /// and so gets the --chmod mode and --fsync like any saved file
+    fn saved(opts: &Opts, path: &Path) -> io::Result<Self> {
+        Ok(ReportWriter {
+            file: Mutex::new(append_saved(opts, path)?),
+            fsync: opts.fsync,
+        })
+    }
+
+    /// Write a multi-line record in one go, so concurrent records don't interleave
+    fn write_record(&self, record: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.write_all(record)?;
+        if self.fsync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&self, line: &str) {
+        if let Err(e) = self.write_record(format!("{}\n", line).as_bytes()) {
+            eprintln!("{}", format!("Failed to write report: {}", e).red());
+        }
+    }
+}
+
+// Define the ResponseData struct to encapsulate response-related data
+struct ResponseData {
+    method: Method,
+    raw_url: String,
+    request_body: Option<String>,
+    raw_request: Option<String>,
+    proxy: Option<String>,
+    redirects: Vec<RedirectHop>,
+    response_body: Bytes,
+    resp_headers: HeaderMap,
+    resp_url: Url,
+    status: StatusCode,
+    version: Version,
+    elapsed: Duration,
+    peer_cert: Option<Vec<u8>>,
+    original_charset: Option<&'static str>,
+    truncated: bool,
+    name_suffix: String,
+    attempt: usize,
+}
+
+#[tokio::main]
+async fn main() {
+    let mut opts = Opts::parse();
+    colored::control::set_override(opts.color.enabled());
+    opts.json |= opts.jsonl_full;
+
+    // The whole of stdin is the body, so URLs come from --input instead
+    if opts.body_from_stdin {
+        let mut body = Vec::new();
+        if let Err(e) = tokio_io::stdin().read_to_end(&mut body).await {
+            eprintln!("{}", format!("Failed to read body from stdin: {}", e).red());
+            std::process::exit(1);
+        }
+        match String::from_utf8(body) {
+            Ok(body) => opts.body = Some(body),
+            Err(e) => opts.raw_body = Some(Bytes::from(e.into_bytes())),
+        }
+    }
+
+    if let Some(ref path) = opts.methods_file {
+        match read_methods(path) {
+            Ok(methods) => opts.methods = methods,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Failed to read methods from {}: {}", path.display(), e).red()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(ref path) = opts.request_file {
+        // Check the request parses up front rather than once per input line
+        let template = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| {
+                if !raw.contains(FUZZ) {
+                    return Err(format!("no {} slot", FUZZ));
+                }
+                ReplayRequest::from_raw(&raw, &opts).map(|_| raw)
+            });
+        match template {
+            Ok(raw) => opts.request_template = Some(raw),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Failed to read request from {}: {}", path.display(), e).red()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(ref path) = opts.cacert {
+        let certs = std::fs::read(path).and_then(|pem| {
+            reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        });
+        match certs {
+            Ok(certs) => opts.root_certs = certs,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Failed to read certificates from {}: {}", path.display(), e).red()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let opts = Arc::new(opts);
+    if opts
+        .doh
+        .as_ref()
+        .is_some_and(|url| !matches!(url.scheme(), "http" | "https"))
+    {
+        eprintln!("{}", "--doh needs an http:// or https:// URL".red());
+        std::process::exit(1);
+    }
+
+    if opts.match_report.is_some()
+        && opts.r#match.is_none()
+        && opts.match_file.is_none()
+        && opts.json_path.is_none()
+    {
+        eprintln!(
+            "{}",
+            "--match-report needs -M, --match-file or --json-path".red()
+        );
+        std::process::exit(1);
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    if opts.interface.is_some() {
+        eprintln!("{}", "--interface is only supported on Linux".red());
+        std::process::exit(1);
+    }
+
+    let clients = match ClientPool::new(&opts) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", format!("Failed to create HTTP client: {}", e).red());
+            std::process::exit(1);
+        }
+    };
+
+    #[cfg(not(unix))]
+    if opts.chmod.is_some() {
+        eprintln!("{}", "--chmod is only supported on Unix; ignoring".yellow());
+    }
+
+    #[cfg(not(unix))]
+    if opts.link_dupes {
+        eprintln!(
+            "{}",
+            "--link-dupes is only supported on Unix; skipping duplicates instead".yellow()
+        );
+    }
+
+    let mut shared = match SharedState::new(&opts) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", format!("Failed to set up output: {}", e).red());
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(ref preflight) = opts.preflight {
+        if let Err(e) = run_preflight(&clients, preflight).await {
+            eprintln!("{}", format!("Preflight check failed: {}", e).red());
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(ref baseline) = opts.baseline {
+        match fetch_baseline(&clients, &opts, baseline).await {
+            Ok(body) => {
+                let body = canonical_body(&opts, &body);
+                shared.baseline = Some(Baseline::new(&body, opts.similarity));
+            }
+            Err(e) => {
+                eprintln!("{}", format!("Failed to fetch baseline: {}", e).red());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Links found by --recursive come back to the dispatcher on their own channel
+    let (links_tx, mut links) = mpsc::unbounded_channel::<String>();
+    if opts.recursive {
+        shared.links = Some(links_tx);
+    }
+
+    let shared = Arc::new(shared);
+
+    let mut pacer = opts
+        .rate
+        .map(|rate| Pacer::new(rate, opts.jitter.unwrap_or(0)));
+
+    let semaphore = Arc::new(Semaphore::new(opts.concurrency.max(1)));
+    let mut tasks = FuturesUnordered::new();
+
+    // Tasks waiting for a capped host's slot hold no global permit, so they
+    // get room of their own to wait in without stalling other hosts
+    let waiting_room = match opts.per_host_concurrency {
+        Some(_) => opts.queue_size.get(),
+        None => 0,
+    };
+    let pending = Arc::new(Semaphore::new(opts.concurrency.max(1) + waiting_room));
+
+    let input: Box<dyn AsyncBufRead + Unpin + Send> = match input_path(&opts) {
+        Some(path) => match tokio_fs::File::open(path).await {
+            Ok(f) => Box::new(tokio_io::BufReader::new(f)),
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("Failed to open {}: {}", path.display(), e).red()
+                );
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(tokio_io::BufReader::new(tokio_io::stdin())),
+    };
+
+    // Input is read ahead into a bounded queue; once it's full the reader
+    // stops pulling lines until the dispatcher catches up
+    let (queue_tx, mut queue) = mpsc::channel::<String>(opts.queue_size.get());
+    let reader = tokio::spawn(async move {
+        let mut lines = input.lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if queue_tx.send(line).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("{}", format!("Error reading input line: {}", e).red());
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut read: usize = 0;
+    let mut dispatched: usize = 0;
+    let mut spawned: usize = 0;
+    let mut requested = HashSet::new();
+    let mut requested_paths = HashSet::new();
+    let mut too_many_errors = false;
+
+    // Only a file can be counted up front; the count is an upper bound, as
+    // comments and filtered lines are never requested
+    let mut progress = opts.progress.then(|| {
+        let total = input_path(&opts).and_then(|path| {
+            let lines = count_lines(path).ok()?.saturating_sub(opts.skip_lines);
+            let lines = opts.limit.map_or(lines, |limit| lines.min(limit));
+            Some(lines * opts.repeat.get())
+        });
+        Progress::new(total, io::stderr().is_terminal() && opts.color.enabled())
+    });
+
+    let run = async {
+        let mut input_done = false;
+
+        // The semaphore alone bounds the work in flight; finished tasks are
+        // reaped as they complete rather than when the set fills up
+        loop {
+            // Running tasks may still find links, so the run only ends once
+            // the input is exhausted, nothing is in flight and no link is queued
+            let found = if input_done && tasks.is_empty() {
+                match links.try_recv() {
+                    Ok(link) => Some(link),
+                    Err(_) => break,
+                }
+            } else {
+                None
+            };
+
+            let (url, replay) = match found {
+                Some(link) => (link, None),
+                None => tokio::select! {
+                    Some(_) = tasks.next(), if !tasks.is_empty() => {
+                        if let Some(ref mut progress) = progress {
+                            progress.tick();
+                        }
+                        continue;
+                    }
+                    Some(link) = links.recv(), if opts.recursive => (link, None),
+                    line = queue.recv(), if !input_done => {
+                        let url = match line {
+                            Some(line) => line,
+                            None => {
+                                input_done = true;
+                                continue;
+                            }
+                        };
+
+                        read += 1;
+                        if read <= opts.skip_lines {
+                            continue;
+                        }
+                        if opts.allow_comments && url.trim_start().starts_with('#') {
+                            continue;
+                        }
+                        if opts.shard.is_some_and(|shard| !shard.includes(read - 1)) {
+                            continue;
+                        }
+                        // Manifest lines are whole requests; the URL stands in for the line
+                        let (url, replay) = if opts.from_manifest.is_some() {
+                            match ReplayRequest::parse(&url) {
+                                Ok(replay) => (replay.url.clone(), Some(Arc::new(replay))),
+                                Err(e) => {
+                                    print_failure(
+                                        &opts,
+                                        &format!("line {}", read),
+                                        "invalid_manifest_line",
+                                        "Invalid manifest entry",
+                                        &e,
+                                    );
+                                    continue;
+                                }
+                            }
+                        } else if let Some(ref template) = opts.request_template {
+                            match ReplayRequest::from_raw(&template.replace(FUZZ, &url), &opts) {
+                                Ok(replay) => (replay.url.clone(), Some(Arc::new(replay))),
+                                Err(e) => {
+                                    print_failure(
+                                        &opts,
+                                        &format!("line {}", read),
+                                        "invalid_request",
+                                        "Invalid request",
+                                        &e,
+                                    );
+                                    continue;
+                                }
+                            }
+                        } else {
+                            (url, None)
+                        };
+                        (url, replay)
+                    }
+                },
+            };
+
+            // Input lines and the links found by --recursive are filtered alike
+            if !in_scope(&opts, &url) {
+                continue;
+            }
+            // URLs that only differ in parameter order collapse into one
+            let url = if opts.sort_query {
+                sort_query(&opts, &url)
+            } else {
+                url
+            };
+            if opts.sort_query && !requested.insert(url.clone()) {
+                continue;
+            }
+            // Only the first URL for each endpoint is requested
+            if opts.dedupe_path && !requested_paths.insert(path_key(&opts, &url)) {
+                continue;
+            }
+
+            if opts.limit.is_some_and(|limit| dispatched >= limit) {
+                break;
+            }
+
+            // Let the previous batch finish and pause before starting the next
+            if let Some(batch_size) = opts.batch_size {
+                if dispatched > 0 && dispatched.is_multiple_of(batch_size.get()) {
+                    while tasks.next().await.is_some() {
+                        if let Some(ref mut progress) = progress {
+                            progress.tick();
+                        }
+                    }
+                    sleep(Duration::from_millis(opts.batch_pause)).await;
+                }
+            }
+            dispatched += 1;
+
+            // Each repeat is a task of its own, paced and limited like any other
+            for attempt in 1..=opts.repeat.get() {
+                if let Some(ref mut pacer) = pacer {
+                    pacer.wait().await;
+                }
+
+                let queued = pending.clone().acquire_owned().await.unwrap();
+
+                // Checked once a slot is free, so the failures of the
+                // requests just finished are counted
+                if opts
+                    .max_errors
+                    .is_some_and(|max| shared.error_count() > max)
+                {
+                    too_many_errors = true;
+                    break;
+                }
+
+                let client = clients.next(&url);
+                let opts = Arc::clone(&opts);
+                let shared = Arc::clone(&shared);
+                let url = url.clone();
+                let replay = replay.clone();
+                let semaphore = Arc::clone(&semaphore);
+                let index = spawned;
+                spawned += 1;
+
+                tasks.push(tokio::spawn(async move {
+                    let reorder = Arc::clone(&shared);
+                    let work = async move {
+                        // The host's slot comes first, so a slow capped host
+                        // only ever ties up as many global permits as it has slots
+                        let host_slot = match input_url(&opts, &url) {
+                            Ok(parsed) => shared.acquire_host_slot(&parsed).await,
+                            Err(_) => None,
+                        };
+                        let permit = semaphore.acquire_owned().await.unwrap();
+
+                        if opts.delay > 0 {
+                            sleep(Duration::from_millis(opts.delay)).await;
+                        }
+                        let methods = match replay {
+                            Some(ref replay) => vec![replay.method.clone()],
+                            None => request_methods(&opts),
+                        };
+                        for url in url_variants(&opts, &url) {
+                            for method in methods.iter().cloned() {
+                                let status = process_url(
+                                    Arc::clone(&client),
+                                    Arc::clone(&opts),
+                                    Arc::clone(&shared),
+                                    url.clone(),
+                                    method.clone(),
+                                    attempt,
+                                    replay.clone(),
+                                )
+                                .await;
+
+                                // Routers and WAFs that disagree on case answer the two differently
+                                let variant = match case_variant(&url) {
+                                    Some(variant) if opts.case_variant => variant,
+                                    _ => continue,
+                                };
+                                let variant_status = process_url(
+                                    Arc::clone(&client),
+                                    Arc::clone(&opts),
+                                    Arc::clone(&shared),
+                                    variant.clone(),
+                                    method,
+                                    attempt,
+                                    replay.clone(),
+                                )
+                                .await;
+                                if let (Some(status), Some(variant_status)) =
+                                    (status, variant_status)
+                                {
+                                    if status != variant_status {
+                                        report_case_difference(
+                                            &opts,
+                                            &url,
+                                            status,
+                                            &variant,
+                                            variant_status,
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                        drop(permit);
+                        drop(host_slot);
+                        drop(queued);
+                    };
+
+                    match reorder.reorder {
+                        Some(ref buffer) => {
+                            let output = RESULT_LINES
+                                .scope(RefCell::new(String::new()), async {
+                                    work.await;
+                                    RESULT_LINES.with(RefCell::take)
+                                })
+                                .await;
+                            buffer.lock().unwrap().push(index, output);
+                        }
+                        None => work.await,
+                    }
+                }));
+            }
+            if too_many_errors {
+                break;
+            }
+        }
+
+        while tasks.next().await.is_some() {
+            if let Some(ref mut progress) = progress {
+                progress.tick();
+            }
+        }
+    };
+
+    let timed_out = match opts.max_time {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), run)
+            .await
+            .is_err(),
+        None => {
+            run.await;
+            false
+        }
+    };
+
+    reader.abort();
+
+    // Cancelled tasks never print, so release what was waiting on them
+    if let Some(ref buffer) = shared.reorder {
+        buffer.lock().unwrap().flush();
+    }
+
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    if let (Some(ref path), Some(ref results)) = (&opts.json_file, &shared.json_results) {
+        let results = serde_json::Value::Array(results.lock().unwrap().clone());
+        if let Err(e) = std::fs::write(path, format!("{:#}\n", results)) {
+            eprintln!(
+                "{}",
+                format!("Failed to write {}: {}", path.display(), e).red()
+            );
+        }
+    }
+
+    if let (true, Some(ref results)) = (opts.report, &shared.json_results) {
+        let path = shared.output_dir.join("index.html");
+        let html = render_report(&shared.output_dir, &results.lock().unwrap());
+        let written = match std::fs::create_dir_all(&shared.output_dir) {
+            Ok(()) => write_file(&opts, &path, html).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            eprintln!(
+                "{}",
+                format!("Failed to write {}: {}", path.display(), e).red()
+            );
+        }
+    }
+
+    if timed_out {
+        for task in tasks.iter() {
+            task.abort();
+        }
+        eprintln!(
+            "{}",
+            format!(
+                "Time limit of {}s reached: stopped after {} requests, cancelled {} in flight",
+                opts.max_time.unwrap_or_default(),
+                dispatched,
+                tasks.len()
+            )
+            .red()
+        );
+    }
+
+    if too_many_errors {
+        eprintln!(
+            "{}",
+            format!(
+                "Too many errors: stopped after {} failed requests (--max-errors {})",
+                shared.error_count(),
+                opts.max_errors.unwrap_or_default()
+            )
+            .red()
+        );
+    }
+
+    let failed_requests = match shared.error_summary(opts.json) {
+        Some(summary) if opts.json => {
+            eprintln!("{}", summary);
+            true
+        }
+        Some(summary) => {
+            eprintln!("{}", summary.red());
+            true
+        }
+        None => false,
+    };
+
+    // Under --expect the run fails if any URL didn't answer as expected
+    if !opts.expect.is_empty() {
+        let unexpected = shared.unexpected.load(Ordering::Relaxed);
+        if unexpected > 0 {
+            eprintln!("{}", format!("Unexpected statuses: {}", unexpected).red());
+        }
+        if unexpected > 0 || failed_requests {
+            std::process::exit(1);
+        }
+    }
+
+    if too_many_errors {
+        std::process::exit(1);
+    }
+}
+
+/// The file URLs are read from, if not stdin
+fn input_path(opts: &Opts) -> Option<&Path> {
+    opts.input.as_deref().or(opts.from_manifest.as_deref())
+}
+
+/// A request as sent, for --manifest; bodies that aren't UTF-8 are base64
+/// encoded, and streamed bodies aren't recorded
+fn request_record(request: &Request) -> serde_json::Value {
+    let headers: Vec<[String; 2]> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            [
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            ]
+        })
+        .collect();
+
+    let mut record = json!({
+        "method": request.method().as_str(),
+        "url": request.url().as_str(),
+        "headers": headers,
+    });
+    if let Some(body) = request.body().and_then(|b| b.as_bytes()) {
+        match std::str::from_utf8(body) {
+            Ok(text) => record["body"] = json!(text),
+            Err(_) => {
+                record["body_base64"] =
+                    json!(base64::engine::general_purpose::STANDARD.encode(body))
+            }
+        }
+    }
+    record
+}
+
+/// A request read back from a --manifest line
+struct ReplayRequest {
+    method: Method,
+    url: String,
+    headers: HeaderMap,
+    body: Option<Vec<u8>>,
+}
+
+impl ReplayRequest {
+    fn parse(line: &str) -> Result<Self, String> {
+        let record: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+
+        let method = record["method"]
+            .as_str()
+            .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+            .ok_or("missing or invalid method")?;
+        let url = record["url"].as_str().ok_or("missing url")?.to_string();
+
+        let mut headers = HeaderMap::new();
+        for pair in record["headers"].as_array().into_iter().flatten() {
+            let (name, value) = match (pair[0].as_str(), pair[1].as_str()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err("invalid header".to_string()),
+            };
+            let name = HeaderName::from_str(name).map_err(|e| e.to_string())?;
+            let value = HeaderValue::from_str(value).map_err(|e| e.to_string())?;
+            headers.append(name, value);
+        }
+
+        let body = match (record["body"].as_str(), record["body_base64"].as_str()) {
+            (Some(text), _) => Some(text.as_bytes().to_vec()),
+            (None, Some(encoded)) => Some(
+                base64::engine::general_purpose::STANDARD
+                    .decode(encoded)
+                    .map_err(|e| e.to_string())?,
+            ),
+            (None, None) => None,
+        };
+
+        Ok(ReplayRequest {
+            method,
+            url,
+            headers,
+            body,
+        })
+    }
+
+    /// Parse a raw HTTP/1 request as written for --request-file. An absolute
+    /// request target is the URL; otherwise it's resolved against --base, or
+    /// against https:// and the Host header without one
+    fn from_raw(raw: &str, opts: &Opts) -> Result<Self, String> {
+        // The head ends at the first blank line, whichever line endings the file uses
+        let (head, body) = ["\r\n\r\n", "\n\n"]
+            .iter()
+            .filter_map(|sep| raw.find(sep).map(|i| (i, sep.len())))
+            .min()
+            .map_or((raw, ""), |(i, len)| (&raw[..i], &raw[i + len..]));
+
+        let mut lines = head.lines();
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method, target),
+            _ => return Err(format!("invalid request line: {}", request_line)),
+        };
+        let method = Method::from_bytes(method.as_bytes()).map_err(|e| e.to_string())?;
+
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("invalid header: {}", line))?;
+            let name = HeaderName::from_str(name.trim()).map_err(|e| e.to_string())?;
+            // The body's length changes with the payload, so the client sets it
+            if name == CONTENT_LENGTH {
+                continue;
+            }
+            let value = HeaderValue::from_str(value.trim()).map_err(|e| e.to_string())?;
+            headers.append(name, value);
+        }
+
+        let url = if Url::parse(target).is_ok() || opts.base.is_some() {
+            target.to_string()
+        } else {
+            let host = headers
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .ok_or("relative request target without a Host header or --base")?;
+            let scheme = match opts.raw_scheme {
+                RawScheme::Http => "http",
+                RawScheme::Https => "https",
+            };
+            format!("{}://{}{}", scheme, host, target)
+        };
+
+        Ok(ReplayRequest {
+            method,
+            url,
+            headers,
+            body: (!body.is_empty()).then(|| body.as_bytes().to_vec()),
+        })
+    }
+}
+
+/// The slot in a --request-file that each input line is substituted into
+const FUZZ: &str = "{FUZZ}";
+
+/// Quote a CSV field if it holds a comma, quote or line break
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Whether an input line passes --include-regex and --exclude-regex
+fn in_scope(opts: &Opts, line: &str) -> bool {
+    (opts.include_regex.is_empty() || opts.include_regex.iter().any(|re| re.is_match(line)))
+        && !opts.exclude_regex.iter().any(|re| re.is_match(line))
+}
+
+/// An input line as a URL, resolved against --base if one was given
+fn input_url(opts: &Opts, line: &str) -> Result<Url, <Url as FromStr>::Err> {
+    match opts.base {
+        Some(ref base) => base.join(line),
+        None => Url::parse(line),
+    }
+}
+
+/// The URL with its query parameters sorted by name, for --sort-query. The
+/// sort is stable, so repeated names keep their relative order, and each
+/// parameter is kept as written, encoding and all
+fn sort_query(opts: &Opts, line: &str) -> String {
+    let mut url = match input_url(opts, line.trim()) {
+        Ok(url) => url,
+        Err(_) => return line.to_string(),
+    };
+
+    let query = match url.query() {
+        Some(query) => query,
+        None => return url.to_string(),
+    };
+    let mut params: Vec<&str> = query.split('&').collect();
+    params.sort_by_key(|param| param.split('=').next().unwrap_or_default());
+    let sorted = params.join("&");
+
+    url.set_query(Some(&sorted));
+    url.to_string()
+}
+
+/// The URL without its query string or fragment, for --dedupe-path
+fn path_key(opts: &Opts, line: &str) -> String {
+    match input_url(opts, line.trim()) {
+        Ok(mut url) => {
+            url.set_query(None);
+            url.set_fragment(None);
+            url.to_string()
+        }
+        Err(_) => line.to_string(),
+    }
+}
+
+/// Completions used for the rate behind --progress's ETA
+const PROGRESS_WINDOW: usize = 50;
+
+/// How often --progress prints a line when it can't redraw one in place
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+tokio::task_local! {
+    /// With --ordered, the stdout of the running task, printed once it's that task's turn
+    static RESULT_LINES: RefCell<String>;
+}
+
+/// Holds the output of tasks that finished early until every task before them has printed
+#[derive(Default)]
+struct ReorderBuffer {
+    next: usize,
+    pending: BTreeMap<usize, String>,
+}
+
+impl ReorderBuffer {
+    /// Record the output of task `index`, printing whatever is now in order
+    fn push(&mut self, index: usize, output: String) {
+        self.pending.insert(index, output);
+
+        let mut stdout = io::stdout().lock();
+        while let Some(output) = self.pending.remove(&self.next) {
+            let _ = stdout.write_all(output.as_bytes());
+            self.next += 1;
+        }
+        let _ = stdout.flush();
+    }
+
+    /// Print everything still held, for when the tasks before it will never finish
+    fn flush(&mut self) {
+        let mut stdout = io::stdout().lock();
+        for output in std::mem::take(&mut self.pending).into_values() {
+            let _ = stdout.write_all(output.as_bytes());
+        }
+        let _ = stdout.flush();
+    }
+}
+
+/// Print to stdout, or into the task's buffer with --ordered
+fn print_output(output: &str) {
+    let buffered = RESULT_LINES
+        .try_with(|lines| lines.borrow_mut().push_str(output))
+        .is_ok();
+    if !buffered {
+        print!("{}", output);
+    }
+}
+
+/// Prints a progress line to stderr as requests complete, redrawn in place
+/// on a terminal and otherwise printed as a new line every PROGRESS_INTERVAL
+struct Progress {
+    total: Option<usize>,
+    done: usize,
+    recent: VecDeque<Instant>,
+    redraw: bool,
+    printed: Option<(usize, Instant)>,
+}
+
+impl Progress {
+    fn new(total: Option<usize>, redraw: bool) -> Self {
+        Self {
+            total,
+            done: 0,
+            recent: VecDeque::with_capacity(PROGRESS_WINDOW),
+            redraw,
+            printed: None,
+        }
+    }
+
+    /// Count a completed request and update the progress
+    fn tick(&mut self) {
+        self.done += 1;
+        if self.recent.len() == PROGRESS_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Instant::now());
+
+        if self.redraw {
+            eprint!("\r\x1b[K{}", self.line());
+        } else if self
+            .printed
+            .is_none_or(|(_, at)| at.elapsed() >= PROGRESS_INTERVAL)
+            || self.total == Some(self.done)
+        {
+            eprintln!("{}", self.line());
+            self.printed = Some((self.done, Instant::now()));
+        }
+    }
+
+    fn line(&self) -> String {
+        // Requests per second over the recent window, once there's a span to measure
+        let rate = match (self.recent.front(), self.recent.back()) {
+            (Some(first), Some(last)) if last > first => {
+                (self.recent.len() - 1) as f64 / (*last - *first).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+
+        let mut line = match self.total {
+            Some(total) => format!(
+                "{}/{} ({}%)",
+                self.done,
+                total,
+                self.done * 100 / total.max(1)
+            ),
+            None => format!("{} done", self.done),
+        };
+        if rate > 0.0 {
+            let _ = write!(line, ", {:.1}/s", rate);
+            if let Some(total) = self.total {
+                let eta = total.saturating_sub(self.done) as f64 / rate;
+                let eta = eta.round() as u64;
+                let _ = write!(line, ", ETA {:02}:{:02}", eta / 60, eta % 60);
+            }
+        }
+
+        line
+    }
+
+    /// End the progress line so later output starts on a fresh one, or
+    /// print the final count if the last line printed is out of date
+    fn finish(self) {
+        if self.redraw {
+            if self.done > 0 {
+                eprintln!();
+            }
+        } else if self.done > 0 && self.printed.is_none_or(|(done, _)| done != self.done) {
+            eprintln!("{}", self.line());
+        }
+    }
+}
+
+fn count_lines(path: &Path) -> io::Result<usize> {
+    let mut reader = io::BufReader::new(File::open(path)?);
+    let mut lines = 0;
+    let mut last = b'\n';
+    loop {
+        let buf = reader.fill_buf()?;
+        let Some(&end) = buf.last() else {
+            break;
+        };
+        lines += buf.iter().filter(|&&b| b == b'\n').count();
+        last = end;
+        let len = buf.len();
+        reader.consume(len);
+    }
+    if last != b'\n' {
+        lines += 1;
+    }
+    Ok(lines)
+}
+
+/// Spaces request starts evenly at --rate, optionally jittering each interval
+struct Pacer {
+    interval: Duration,
+    jitter: f64,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    fn new(rate: f64, jitter_percent: u8) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            jitter: f64::from(jitter_percent) / 100.0,
+            next: None,
+        }
+    }
+
+    /// Wait for the next slot in the schedule
+    async fn wait(&mut self) {
+        let now = Instant::now();
+        let slot = match self.next {
+            Some(next) if next > now => {
+                sleep(next - now).await;
+                next
+            }
+            // Don't let a stall in reading input turn into a burst
+            _ => now,
+        };
+
+        // A symmetric perturbation keeps the average interval at 1/rate
+        let factor = if self.jitter > 0.0 {
+            1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            1.0
+        };
+        self.next = Some(slot + self.interval.mul_f64(factor));
+    }
+}
+
+/// Check that <url> answers with a 2xx through every configured client
+async fn run_preflight(clients: &ClientPool, url: &str) -> Result<(), String> {
+    for client in &clients.clients {
+        let via = match client.proxy {
+            Some(ref proxy) => format!(" via {}", proxy),
+            None => String::new(),
+        };
+
+        let resp = client
+            .http
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| format!("{}{}: {}", url, via, error_causes(&e)))?;
+
+        if !resp.status().is_success() {
+            return Err(format!("{}{} returned {}", url, via, resp.status()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Body of the --baseline page that responses are compared against
+struct Baseline {
+    hash: u64,
+    text: String,
+    similarity: Option<f32>,
+}
+
+impl Baseline {
+    fn new(body: &[u8], similarity: Option<f32>) -> Self {
+        Baseline {
+            hash: xxh3_64(body),
+            text: String::from_utf8_lossy(body).into_owned(),
+            similarity,
+        }
+    }
+
+    /// Whether a body is the baseline page, exactly or within --similarity
+    fn matches(&self, body: &[u8]) -> bool {
+        if xxh3_64(body) == self.hash {
+            return true;
+        }
+
+        let threshold = match self.similarity {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+
+        // The ratio can't beat 2 * shorter / total, so skip the diff when
+        // the lengths alone rule a match out
+        let (a, b) = (self.text.len(), body.len());
+        let best_ratio = 2.0 * a.min(b) as f32 / (a + b).max(1) as f32;
+        if best_ratio < threshold {
+            return false;
+        }
+
+        let text = String::from_utf8_lossy(body);
+        let ratio = TextDiff::configure()
+            .timeout(Duration::from_millis(100))
+            .diff_chars(self.text.as_str(), &text)
+            .ratio();
+        ratio >= threshold
+    }
+}
+
+/// Fetch the --baseline URL with the scan's headers, returning its body
+async fn fetch_baseline(clients: &ClientPool, opts: &Opts, url: &str) -> Result<Bytes, String> {
+    let mut req = clients.clients[0].http.get(url);
+    if let Some(headers) = parse_headers(&opts.header) {
+        req = req.headers(headers);
+    }
+
+    let resp = req
+        .send()
+        .await
+        .map_err(|e| format!("{}: {}", url, error_causes(&e)))?;
+    resp.bytes()
+        .await
+        .map_err(|e| format!("{}: {}", url, error_causes(&e)))
+}
+
+/// An HTTP client and the proxy it sends requests through
+struct ProxyClient {
+    http: Client,
+    proxy: Option<String>,
+}
+
+/// Clients that requests are spread across round-robin, one per proxy,
+/// except for hosts that --proxy-map sends through a proxy of their own
+struct ClientPool {
+    clients: Vec<Arc<ProxyClient>>,
+    next: AtomicUsize,
+    by_host: HashMap<String, Arc<ProxyClient>>,
+    base: Option<Url>,
+}
+
+impl ClientPool {
+    fn new(opts: &Opts) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut proxies: Vec<Option<String>> = opts.proxy.iter().cloned().map(Some).collect();
+
+        if let Some(ref path) = opts.proxy_file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            proxies.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(|l| Some(l.to_string())),
+            );
+        }
+
+        if proxies.is_empty() {
+            proxies.push(None);
+        }
+
+        let clients: Vec<Arc<ProxyClient>> = proxies
+            .into_iter()
+            .map(|proxy| {
+                let http = new_client(opts, proxy.as_deref())?;
+                Ok(Arc::new(ProxyClient { http, proxy }))
+            })
+            .collect::<Result<_, reqwest::Error>>()?;
+
+        // Hosts mapped to the same proxy share its client and connections
+        let mut by_host = HashMap::new();
+        if let Some(ref path) = opts.proxy_map {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            let mut by_proxy: HashMap<String, Arc<ProxyClient>> = clients
+                .iter()
+                .filter_map(|c| Some((c.proxy.clone()?, Arc::clone(c))))
+                .collect();
+
+            for line in contents.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut parts = line.split_whitespace().filter(|p| *p != "->");
+                let (host, proxy) = match (parts.next(), parts.next()) {
+                    (Some(host), Some(proxy)) => (host, proxy),
+                    _ => return Err(format!("invalid proxy map line: {}", line).into()),
+                };
+
+                let client = match by_proxy.entry(proxy.to_string()) {
+                    Entry::Occupied(entry) => Arc::clone(entry.get()),
+                    Entry::Vacant(entry) => {
+                        let http = new_client(opts, Some(proxy))?;
+                        let proxy = Some(proxy.to_string());
+                        Arc::clone(entry.insert(Arc::new(ProxyClient { http, proxy })))
+                    }
+                };
+                by_host.insert(host.to_ascii_lowercase(), client);
+            }
+        }
+
+        Ok(ClientPool {
+            clients,
+            next: AtomicUsize::new(0),
+            by_host,
+            base: opts.base.clone(),
+        })
+    }
+
+    /// The client for an input URL: its host's mapped proxy, or the next in the rotation
+    fn next(&self, raw_url: &str) -> Arc<ProxyClient> {
+        if !self.by_host.is_empty() {
+            let url = match self.base {
+                Some(ref base) => base.join(raw_url),
+                None => Url::parse(raw_url),
+            };
+            let host = url
+                .ok()
+                .and_then(|u| u.host_str().map(str::to_ascii_lowercase));
+            if let Some(client) = host.and_then(|host| self.by_host.get(&host)) {
+                return Arc::clone(client);
+            }
+        }
+
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        Arc::clone(&self.clients[i])
+    }
+}
+
+/// Resolves host names with a DNS-over-HTTPS server's JSON API
+/// (application/dns-json), as served by Cloudflare and Google
+struct DohResolver {
+    client: Client,
+    url: Url,
+}
+
+impl DohResolver {
+    fn new(url: Url) -> Result<Self, reqwest::Error> {
+        // The resolver's own host is looked up with the system resolver
+        let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+        Ok(DohResolver { client, url })
+    }
+
+    /// Addresses from the resolver's answer for one record type (1 = A, 28 = AAAA)
+    async fn query(
+        client: &Client,
+        url: &Url,
+        name: &str,
+        record_type: u64,
+    ) -> Result<Vec<IpAddr>, Box<dyn std::error::Error + Send + Sync>> {
+        let resp = client
+            .get(url.clone())
+            .query(&[("name", name), ("type", &record_type.to_string())])
+            .header(ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?;
+        let answer: serde_json::Value = serde_json::from_slice(&resp.bytes().await?)?;
+
+        // CNAMEs come back in the same answer, so only the address records are kept
+        Ok(answer["Answer"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|record| record["type"].as_u64() == Some(record_type))
+            .filter_map(|record| record["data"].as_str()?.parse().ok())
+            .collect())
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let client = self.client.clone();
+        let url = self.url.clone();
+        Box::pin(async move {
+            let name = name.as_str();
+            let (v4, v6) = tokio::join!(
+                DohResolver::query(&client, &url, name, 1),
+                DohResolver::query(&client, &url, name, 28)
+            );
+
+            let mut addrs = Vec::new();
+            let mut error = None;
+            for result in [v4, v6] {
+                match result {
+                    Ok(found) => addrs.extend(found),
+                    Err(e) => error = Some(e),
+                }
+            }
+            if addrs.is_empty() {
+                return Err(error
+                    .unwrap_or_else(|| format!("no addresses for {} from {}", name, url).into()));
+            }
+
+            // The client fills in the port from the URL
+            let addrs: Addrs = Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+fn new_client(opts: &Opts, proxy: Option<&str>) -> Result<Client, reqwest::Error> {
+    client_builder(opts, proxy)?.build()
+}
+
+fn client_builder(opts: &Opts, proxy: Option<&str>) -> Result<ClientBuilder, reqwest::Error> {
+    let mut builder = Client::builder().timeout(Duration::from_secs(10));
+
+    // Scanning usually wants whatever the server presents, but the chain
+    // can still be checked when only the name is expected to be wrong
+    if opts.accept_invalid_hostnames {
+        builder = builder.danger_accept_invalid_hostnames(true);
+        for cert in &opts.root_certs {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+    } else {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    if !opts.keep_alive {
+        builder = builder.pool_idle_timeout(Duration::from_secs(0));
+    }
+
+    if let Some(max_idle) = opts.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+
+    if let Some(proxy_url) = proxy {
+        builder = builder.proxy(Proxy::all(proxy_url)?);
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(ref interface) = opts.interface {
+        builder = builder.interface(interface);
+    }
+
+    if let Some(ref url) = opts.doh {
+        builder = builder.dns_resolver(Arc::new(DohResolver::new(url.clone())?));
+    }
+
+    // The client takes the port from the URL, whatever the address says
+    for pinned in &opts.resolve {
+        builder = builder.resolve(&pinned.host, SocketAddr::new(pinned.addr, 0));
+    }
+
+    // Redirects are followed by hand so each hop can be recorded
+    if opts.record_redirects {
+        builder = builder.redirect(reqwest::redirect::Policy::none());
+    } else if opts.same_host_redirects {
+        builder = builder.redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() > MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if attempt.url().host_str() != attempt.previous()[0].host_str() {
+                attempt.stop()
+            } else {
+                attempt.follow()
+            }
+        }));
+    }
+
+    if opts.save_cert {
+        builder = builder.tls_info(true);
+    }
+
+    // Dropping the decoders stops the client adding Accept-Encoding
+    if !auto_decompress(opts) {
+        builder = builder.no_gzip().no_brotli().no_deflate();
+    }
+
+    if let Some(browser) = opts.impersonate {
+        builder = builder
+            .user_agent(browser.user_agent())
+            .default_headers(browser.default_headers())
+            .min_tls_version(reqwest::tls::Version::TLS_1_2)
+            .max_tls_version(reqwest::tls::Version::TLS_1_3);
+    }
+
+    // Set after the browser's defaults so it replaces their Accept-Encoding
+    if let Some(ref value) = opts.accept_encoding {
+        if !value.is_empty() {
+            builder =
+                builder.default_headers(HeaderMap::from_iter([(ACCEPT_ENCODING, value.clone())]));
+        }
+    }
+
+    // HTTP/1 header limits are fixed by hyper; overflows are reported per URL
+    if let Some(size) = opts.max_header_size {
+        builder = builder.http2_max_header_list_size(size);
+    }
+
+    Ok(builder)
+}
+
+/// A client and URL for sending the TLS server name `sni` to `url`'s host.
+/// rustls takes the server name from the URL's host and offers no override,
+/// so the URL carries the SNI name and a client per host and port resolves
+/// that name to the real host's address. Clients are built once and kept in
+/// `shared`, so their connections are reused.
+async fn sni_client(
+    opts: &Opts,
+    shared: &SharedState,
+    url: &Url,
+    sni: &str,
+) -> Result<(Client, Url), String> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let mut sni_url = url.clone();
+    sni_url
+        .set_host(Some(sni))
+        .map_err(|e| format!("invalid SNI name {}: {}", sni, e))?;
+
+    let key = (host.to_string(), port);
+    if let Some(client) = shared.sni_clients.lock().unwrap().get(&key) {
+        return Ok((client.clone(), sni_url));
+    }
+
+    // --resolve pins the real host's address just as it would without --sni
+    let pinned = opts
+        .resolve
+        .iter()
+        .find(|pinned| pinned.host.eq_ignore_ascii_case(host));
+    let addr = match pinned {
+        Some(pinned) => SocketAddr::new(pinned.addr, port),
+        None => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("failed to resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("no addresses for {}", host))?,
+    };
+
+    let client = client_builder(opts, None)
+        .and_then(|builder| builder.resolve(sni, addr).build())
+        .map_err(|e| e.to_string())?;
+
+    // Tasks racing to build the same client all use the first one stored
+    let client = shared
+        .sni_clients
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert(client)
+        .clone();
+
+    Ok((client, sni_url))
+}
+
+/// Whether the client asks for and decompresses gzip, brotli and deflate bodies
+fn auto_decompress(opts: &Opts) -> bool {
+    !opts.no_default_headers
+        && !opts.no_auto_decompress
+        && opts.accept_encoding.as_ref().is_none_or(|v| !v.is_empty())
+}
+
+/// The URLs requested for an input line: the line itself, then with
+/// --https-upgrade its https:// variant if it's an http:// URL
+fn url_variants(opts: &Opts, line: &str) -> Vec<String> {
+    let mut variants = vec![line.to_string()];
+
+    if opts.https_upgrade {
+        let trimmed = line.trim_start();
+        if trimmed
+            .get(..7)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        {
+            variants.push(format!("https://{}", &trimmed[7..]));
+        }
+    }
+
+    variants
+}
+
+/// The URL with the letters of its path randomly recased, for --case-variant;
+/// None if the path has no letters to recase. Percent escapes are left alone
+fn case_variant(line: &str) -> Option<String> {
+    let start = match line.find("://") {
+        Some(i) => line[i + 3..].find('/').map_or(line.len(), |j| i + 3 + j),
+        None => 0,
+    };
+    let end = line[start..]
+        .find(['?', '#'])
+        .map_or(line.len(), |i| start + i);
+
+    let mut rng = rand::thread_rng();
+    let mut variant = String::with_capacity(line.len());
+    let mut letters = Vec::new();
+    let mut escape = 0;
+    for (i, c) in line.char_indices() {
+        if c == '%' {
+            escape = 2;
+        } else if escape > 0 {
+            escape -= 1;
+        } else if (start..end).contains(&i) && c.is_ascii_alphabetic() {
+            letters.push(variant.len());
+            let flipped = if rng.gen() { swap_ascii_case(c) } else { c };
+            variant.push(flipped);
+            continue;
+        }
+        variant.push(c);
+    }
+
+    // Make sure at least one letter changed
+    if variant == line {
+        let i = *letters.first()?;
+        let c = swap_ascii_case(variant.as_bytes()[i] as char);
+        variant.replace_range(i..=i, c.encode_utf8(&mut [0; 4]));
+    }
+    Some(variant)
+}
+
+fn swap_ascii_case(c: char) -> char {
+    if c.is_ascii_uppercase() {
+        c.to_ascii_lowercase()
+    } else {
+        c.to_ascii_uppercase()
+    }
+}
+
+/// Report a --case-variant URL that answered differently from the URL it was made from
+fn report_case_difference(
+    opts: &Opts,
+    url: &str,
+    status: StatusCode,
+    variant: &str,
+    variant_status: StatusCode,
+) {
+    let report = if opts.json {
+        json!({
+            "url": variant,
+            "case_variant_of": url,
+            "status": variant_status.as_u16(),
+            "original_status": status.as_u16(),
+        })
+        .to_string()
+    } else {
+        format!(
+            "{} [case differs: {} vs {} for {}]",
+            variant,
+            variant_status.as_u16(),
+            status.as_u16(),
+            url
+        )
+        .yellow()
+        .to_string()
+    };
+    print_output(&format!("{}\n", report));
+}
+
+/// The methods each URL is requested with, in order
+fn request_methods(opts: &Opts) -> Vec<Method> {
+    if opts.both_methods {
+        vec![Method::HEAD, Method::GET]
+    } else if !opts.methods.is_empty() {
+        opts.methods.clone()
+    } else {
+        vec![request_method(opts)]
+    }
+}
+
+/// Read --methods-file, skipping blank lines and '#' comments
+fn read_methods(path: &Path) -> io::Result<Vec<Method>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut methods = Vec::new();
+
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let method = Method::from_bytes(line.as_bytes()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid method: {}", line),
+            )
+        })?;
+        methods.push(method);
+    }
+
+    if methods.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no methods listed",
+        ));
+    }
+
+    Ok(methods)
+}
+
+/// The method to send, switching the default GET to POST when there's a body
+fn request_method(opts: &Opts) -> Method {
+    let mut method = opts.method.clone();
+
+    let has_body = opts.body.is_some() || opts.raw_body.is_some() || opts.body_file.is_some();
+    if has_body && method.eq_ignore_ascii_case("GET") {
+        method = "POST".to_string();
+    }
+
+    method.parse::<Method>().unwrap_or(Method::GET)
+}
+
+/// Request a URL and handle the response, returning its status if one came back
+async fn process_url(
+    client: Arc<ProxyClient>,
+    opts: Arc<Opts>,
+    shared: Arc<SharedState>,
+    raw_url: String,
+    method: Method,
+    attempt: usize,
+    replay: Option<Arc<ReplayRequest>>,
+) -> Option<StatusCode> {
+    let url = match input_url(&opts, &raw_url) {
+        Ok(u) => u,
+        Err(e) => {
+            print_failure(
+                &opts,
+                &raw_url,
+                "invalid_url",
+                "Invalid URL",
+                &e.to_string(),
+            );
+            return None;
+        }
+    };
+
+    if opts.strict_url && !matches!(url.scheme(), "http" | "https") {
+        print_failure(
+            &opts,
+            &raw_url,
+            "unsupported_scheme",
+            "Unsupported scheme",
+            url.scheme(),
+        );
+        return None;
+    }
+
+    // Report and name relative inputs by the URL they resolved to
+    let raw_url = if opts.base.is_some() {
+        url.to_string()
+    } else {
+        raw_url
+    };
+
+    if shared.is_broken(&url) {
+        print_failure(
+            &opts,
+            &raw_url,
+            "circuit_open",
+            "Skipped",
+            "too many failing responses from this host",
+        );
+        return None;
+    }
+
+    let request_body = match replay {
+        Some(ref replay) => replay
+            .body
+            .as_deref()
+            .map(|b| String::from_utf8_lossy(b).into_owned()),
+        None => match opts.raw_body {
+            Some(ref body) => Some(String::from_utf8_lossy(body).into_owned()),
+            None => opts.body.as_deref().map(|b| render_body(b, &url)),
+        },
+    };
+
+    let (http, request_url) = match opts.sni {
+        Some(ref sni) if url.scheme() == "https" => {
+            match sni_client(&opts, &shared, &url, sni).await {
+                Ok(pair) => pair,
+                Err(e) => {
+                    print_failure(&opts, &raw_url, "connect", "Connection failed", &e);
+                    return None;
+                }
+            }
+        }
+        _ => (client.http.clone(), url.clone()),
+    };
+
+    let mut req = http.request(method.clone(), request_url.clone());
+
+    // The URL now names the SNI host, so the real one goes in Host unless -H sets it
+    if request_url != url && !opts.header.iter().any(|h| is_header(h, HOST)) {
+        let mut host = url.host_str().unwrap_or_default().to_string();
+        if let Some(port) = url.port() {
+            let _ = write!(host, ":{}", port);
+        }
+        req = req.header(HOST, host);
+    }
+
+    // Add headers, in a fresh order per request if shuffling
+    let headers = match shared.header_rng {
+        Some(ref rng) => {
+            let mut shuffled = opts.header.clone();
+            shuffled.shuffle(&mut *rng.lock().unwrap());
+            parse_headers(&shuffled)
+        }
+        None => parse_headers(&opts.header),
+    };
+    if let Some(headers) = headers {
+        req = req.headers(headers);
+    }
+    if let Some(ref replay) = replay {
+        req = req.headers(replay.headers.clone());
+    }
+
+    // Set as a map so it replaces a Host from -H or the request file rather than adding one
+    if let Some(ref host) = opts.host {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, host.clone());
+        req = req.headers(headers);
+    }
+
+    // Tag the request so it can be found in the server's logs
+    if let Some(ref name) = opts.trace_header {
+        let trace_id = random_uuid();
+        req = req.header(name, &trace_id);
+
+        let line = format!("{} -> {}", raw_url, trace_id);
+        match shared.trace_log {
+            Some(ref log) => log.write_line(&line),
+            None if opts.json => eprintln!("{}", json!({ "url": raw_url, "trace_id": trace_id })),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    // Add body: as recorded when replaying, or gzipped if asked to
+    if let Some(body) = replay.as_ref().and_then(|r| r.body.clone()) {
+        req = req.body(body);
+    } else if let Some(body) = opts
+        .raw_body
+        .clone()
+        .or_else(|| request_body.clone().map(Bytes::from))
+    {
+        if opts.compress_request {
+            match gzip(&body) {
+                Ok(compressed) => req = req.header(CONTENT_ENCODING, "gzip").body(compressed),
+                Err(e) => {
+                    print_failure(
+                        &opts,
+                        &raw_url,
+                        "compress",
+                        "Failed to compress body",
+                        &e.to_string(),
+                    );
+                    return None;
+                }
+            }
+        } else {
+            req = req.body(body);
+        }
+    }
+
+    // A streamed body can't be replayed, so it isn't retried or resent on redirects
+    if let Some(ref path) = opts.body_file {
+        match tokio_fs::File::open(path).await {
+            Ok(file) if opts.compress_request => {
+                req = req
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(reqwest::Body::wrap_stream(gzip_stream(file)))
+            }
+            Ok(file) => req = req.body(file),
+            Err(e) => {
+                print_failure(
+                    &opts,
+                    &raw_url,
+                    "other",
+                    "Failed to open body file",
+                    &e.to_string(),
+                );
+                return None;
+            }
+        }
+    }
+
+    let request = match req.build() {
+        Ok(r) => r,
+        Err(e) => {
+            print_failure(&opts, &raw_url, "other", "Request failed", &e.to_string());
+            return None;
+        }
+    };
+
+    if let Some(ref manifest) = shared.request_manifest {
+        manifest.write_line(&request_record(&request).to_string());
+    }
+
+    let raw_request = if opts.save_request {
+        Some(render_request(&opts, &request))
+    } else {
+        None
+    };
+
+    // Send the request, sending it again while the body comes back blank or the
+    // connection drops mid-response if asked to
+    let started = Instant::now();
+    let mut redirects = Vec::new();
+    let mut retries_left = if opts.retry_empty { opts.retries } else { 0 };
+    let mut resets_left = if opts.retry_connection_reset {
+        opts.retries
+    } else {
+        0
+    };
+    let mut request = request;
+    let (resp_parts, peer_cert, response_body, truncated, ttfb) = loop {
+        let retry = if retries_left > 0 || resets_left > 0 {
+            request.try_clone()
+        } else {
+            None
+        };
+
+        redirects.clear();
+        let resp = match send_request(&http, &opts, &shared, request, &mut redirects).await {
+            Ok(r) => r,
+            Err(e) => match retry {
+                Some(retry) if resets_left > 0 && is_connection_reset(&e) => {
+                    resets_left -= 1;
+                    request = retry;
+                    continue;
+                }
+                _ => {
+                    report_request_error(&opts, &shared, &raw_url, &e);
+                    return None;
+                }
+            },
+        };
+        // The response head is the first thing back from the server
+        let ttfb = started.elapsed();
+
+        // Filter on the declared length before downloading the body
+        if !content_length_allowed(&opts, resp.headers()) {
+            check_expected(&opts, &shared, &raw_url, resp.status());
+            print_result(
+                &opts,
+                &shared,
+                &raw_url,
+                resp.status(),
+                Outcome::Seen,
+                &[],
+                None,
+            );
+            return Some(resp.status());
+        }
+
+        let parts = (
+            resp.status(),
+            resp.version(),
+            resp.headers().clone(),
+            resp.url().clone(),
+        );
+
+        // Only the leaf certificate is exposed, and only when tls_info is enabled
+        let peer_cert = resp
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .map(<[u8]>::to_vec);
+
+        // Dropping the response closes the connection instead of reading the body off it
+        if opts.drop_body {
+            drop(resp);
+            break (parts, peer_cert, Bytes::new(), false, ttfb);
+        }
+
+        let (response_body, truncated) = match read_body(resp, opts.max_body_size).await {
+            Ok(read) => read,
+            Err(e) if resets_left > 0 && retry.is_some() && is_connection_reset(&e) => {
+                resets_left -= 1;
+                request = retry.unwrap();
+                continue;
+            }
+            Err(e) => {
+                let kind = RequestErrorKind::of(&e);
+                shared.record_error(kind);
+                print_failure(
+                    &opts,
+                    &raw_url,
+                    kind.label(),
+                    "Failed to read body",
+                    &e.to_string(),
+                );
+                return None;
+            }
+        };
+
+        match retry {
+            Some(retry) if retries_left > 0 && is_blank(&response_body) => {
+                retries_left -= 1;
+                request = retry;
+            }
+            _ => break (parts, peer_cert, response_body, truncated, ttfb),
+        }
+    };
+
+    // Extract response data
+    let (status, version, resp_headers, mut resp_url) = resp_parts;
+
+    // Save and report under the URL's own host rather than the SNI name
+    if request_url != url && resp_url.host_str() == request_url.host_str() {
+        let _ = resp_url.set_host(url.host_str());
+    }
+
+    check_expected(&opts, &shared, &raw_url, status);
+
+    if let Some(ref csv) = shared.timing_csv {
+        csv.write_line(&format!(
+            "{},{},,,{},{}",
+            csv_field(&raw_url),
+            status.as_u16(),
+            ttfb.as_millis(),
+            started.elapsed().as_millis()
+        ));
+    }
+
+    // Correlate the URL with the id the server gave it
+    if let Some(value) = opts
+        .log_response_header
+        .as_ref()
+        .and_then(|name| resp_headers.get(name))
+    {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        let line = format!("{} -> {}", raw_url, value);
+        match shared.response_header_log {
+            Some(ref log) => log.write_line(&line),
+            None if opts.json => eprintln!("{}", json!({ "url": raw_url, "header": value })),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    if let Some(breaker) = shared.record_status(&opts, &url, status) {
+        let host = url.host_str().unwrap_or_default();
+        print_notice(
+            &opts,
+            host,
+            "circuit_open",
+            &format!(
+                "{} answered {} {} times, skipping its remaining URLs",
+                host, breaker.status, breaker.count
+            ),
+        );
+    }
+
+    // Create ResponseData instance
+    let mut response_data = ResponseData {
+        method: method.clone(),
+        raw_url: raw_url.clone(),
+        request_body,
+        raw_request,
+        proxy: client.proxy.clone(),
+        redirects,
+        response_body,
+        resp_headers,
+        resp_url,
+        status,
+        version,
+        elapsed: started.elapsed(),
+        peer_cert,
+        original_charset: None,
+        truncated,
+        name_suffix: String::new(),
+        attempt,
+    };
+
+    // Follow HTML meta refresh redirects, which the client doesn't do itself
+    if opts.meta_refresh {
+        if let Err(e) = follow_meta_refresh(&http, &opts, &mut response_data).await {
+            report_request_error(&opts, &shared, &raw_url, &e);
+            return None;
+        }
+        response_data.elapsed = started.elapsed();
+    }
+    let status = response_data.status;
+
+    // Queue the entries of an open directory, whether or not the listing itself is kept
+    if opts.recursive {
+        shared.queue_listing(&opts, &raw_url, &response_data);
+    }
+
+    if opts.decode_charset {
+        if let Some((decoded, charset)) =
+            decode_charset(&response_data.resp_headers, &response_data.response_body)
+        {
+            response_data.response_body = decoded;
+            response_data.original_charset = Some(charset);
+        }
+    }
+
+    if let Some(ref report) = shared.cookies_report {
+        for cookie in response_data.resp_headers.get_all(SET_COOKIE) {
+            let cookie = String::from_utf8_lossy(cookie.as_bytes());
+            report.write_line(&format!("{} {}", raw_url, describe_cookie(&cookie)));
+        }
+    }
+
+    if let (Some(ref re), Some(ref output)) = (&opts.extract, &shared.extract_output) {
+        let mut found: Vec<&[u8]> = re
+            .find_iter(&response_data.response_body)
+            .map(|m| m.as_bytes())
+            .collect();
+        found.sort_unstable();
+        found.dedup();
+        for m in found {
+            output.write_line(&format!("{}\t{}", raw_url, String::from_utf8_lossy(m)));
+        }
+    }
+
+    let mut should_save =
+        opts.save || (!opts.save_status.is_empty() && opts.save_status.contains(&status.as_u16()));
+
+    // Check if response is HTML
+    if opts.ignore_html && is_html(&response_data.response_body) {
+        should_save = false;
+    }
+
+    // Check if response body is empty or whitespace
+    if opts.ignore_empty && is_blank(&response_data.response_body) {
+        should_save = false;
+    }
+
+    // Check if response body contains the match string(s)
+    let matched = if let Some(ref matcher) = shared.matcher {
+        Some(matcher.matches(&response_data.response_body))
+    } else {
+        opts.r#match
+            .as_ref()
+            .map(|m| twoway::find_bytes(&response_data.response_body, m.as_bytes()).is_some())
+    };
+    let matched = match opts.json_path {
+        Some(ref path) => Some(
+            matched.unwrap_or(true)
+                && path.matches(&response_data.response_body, opts.json_value.as_deref()),
+        ),
+        None => matched,
+    };
+    if let Some(matched) = matched {
+        // With a match report, matching alone reports; saving still takes -S or -s
+        match shared.match_report {
+            Some(ref report) => {
+                if matched {
+                    report.write_line(&raw_url);
+                }
+                should_save = should_save && matched;
+            }
+            None => should_save = matched,
+        }
+    }
+
+    // Never save skipped statuses, whatever the other flags say
+    if opts
+        .skip_status
+        .iter()
+        .any(|range| range.contains(status.as_u16()))
+    {
+        should_save = false;
+    }
+
+    // Drop soft-404s: responses matching the known-bad baseline page
+    if let Some(ref baseline) = shared.baseline {
+        if baseline.matches(&canonical_body(&opts, &response_data.response_body)) {
+            return Some(status);
+        }
+    }
+
+    // Extra details appended to the printed line
+    let mut notes = Vec::new();
+
+    if opts.both_methods || opts.methods_file.is_some() {
+        notes.push(method.to_string());
+    }
+
+    if opts.repeat.get() > 1 {
+        notes.push(format!("attempt {}", attempt));
+    }
+
+    if opts.https_upgrade {
+        notes.push(url.scheme().to_string());
+    }
+
+    if response_data.truncated {
+        notes.push(format!(
+            "truncated at {} bytes",
+            opts.max_body_size.unwrap_or_default()
+        ));
+    }
+
+    let elapsed_ms = response_data.elapsed.as_millis();
+    if opts.slow_threshold.is_some_and(|ms| elapsed_ms > ms.into()) {
+        notes.push(format!("SLOW {}ms", elapsed_ms));
+        if let Some(ref log) = shared.slow_log {
+            log.write_line(&format!("{} {}", raw_url, elapsed_ms));
+        }
+    }
+
+    // Compare against a prior capture, dropping responses that haven't changed
+    let mut diff = None;
+    if let Some(ref prior_dir) = opts.diff {
+        let (response_dir, hash_hex) = response_location(&opts, &response_data);
+        let prior_body = prior_dir.join(response_dir).join(format!(
+            "{}.{}",
+            hash_hex,
+            body_extension(&opts, &response_data)
+        ));
+
+        match tokio_fs::read(&prior_body).await {
+            Ok(prior)
+                if canonical_body(&opts, &prior)
+                    == canonical_body(&opts, &response_data.response_body) =>
+            {
+                return Some(status)
+            }
+            Ok(prior) => {
+                notes.push("changed".to_string());
+                if opts.show_diff {
+                    diff = unified_diff(&prior, &response_data.response_body);
+                }
+            }
+            Err(_) => notes.push("new".to_string()),
+        }
+    }
+
+    if opts.titles && is_html(&response_data.response_body) {
+        if let Some(title) = extract_title(&response_data.response_body) {
+            notes.push(title);
+        }
+    }
+
+    if opts.detect_waf {
+        if let Some(waf) = detect_waf(&response_data.resp_headers, &response_data.response_body) {
+            notes.push(format!("WAF: {}", waf));
+        }
+    }
+
+    for name in &opts.show_header {
+        let values: Vec<_> = response_data
+            .resp_headers
+            .get_all(name)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()))
+            .collect();
+        if !values.is_empty() {
+            notes.push(format!("{}: {}", name, values.join(", ")));
+        }
+    }
+
+    if !should_save {
+        print_result(
+            &opts,
+            &shared,
+            &raw_url,
+            status,
+            Outcome::Seen,
+            &notes,
+            diff.as_deref(),
+        );
+        return Some(status);
+    }
+
+    // The result line carries the whole response, so nothing is written to disk
+    if opts.jsonl_full {
+        print_result(
+            &opts,
+            &shared,
+            &raw_url,
+            status,
+            Outcome::Inline(&response_data),
+            &notes,
+            diff.as_deref(),
+        );
+        return Some(status);
+    }
+
+    // Identical bodies are saved once; later copies are skipped or linked
+    if opts.dedupe_body {
+        let (response_dir, hash_hex) = response_location(&opts, &response_data);
+        let body_path = response_dir.join(format!(
+            "{}.{}",
+            hash_hex,
+            body_extension(&opts, &response_data)
+        ));
+
+        let body = canonical_body(&opts, &response_data.response_body);
+        if let Some(original) = shared.claim_body(&body, &body_path) {
+            if !opts.link_dupes || cfg!(not(unix)) {
+                notes.push("duplicate".to_string());
+                print_result(
+                    &opts,
+                    &shared,
+                    &raw_url,
+                    status,
+                    Outcome::Seen,
+                    &notes,
+                    None,
+                );
+                return Some(status);
+            }
+
+            match link_duplicate(&shared.output_dir, &body_path, &original).await {
+                Ok(()) => {
+                    notes.push(format!("duplicate of {}", original.display()));
+                    let outcome = Outcome::Linked(&original);
+                    print_result(&opts, &shared, &raw_url, status, outcome, &notes, None);
+                }
+                Err(e) => print_failure(
+                    &opts,
+                    &raw_url,
+                    "link",
+                    "Failed to link duplicate",
+                    &e.to_string(),
+                ),
+            }
+            return Some(status);
+        }
+    }
+
+    // Keep distinct requests that hash to the same name apart
+    if opts.on_collision != Collision::Overwrite && !opts.per_host_file {
+        let (response_dir, hash_hex) = response_location(&opts, &response_data);
+        let request = xxh3_64(request_key(&opts, &response_data).as_bytes());
+        match shared.claim_name(&opts, &response_dir.join(hash_hex), request) {
+            Some(suffix) => response_data.name_suffix = suffix,
+            None => {
+                notes.push("collision".to_string());
+                print_result(
+                    &opts,
+                    &shared,
+                    &raw_url,
+                    status,
+                    Outcome::Seen,
+                    &notes,
+                    None,
+                );
+                return Some(status);
+            }
+        }
+    }
+
+    let saved = if opts.per_host_file {
+        shared.append_to_host_file(&opts, &response_data)
+    } else {
+        save_response(&opts, &shared, &response_data).await
+    };
+    let body_filename = match saved {
+        Ok(path) => path,
+        Err(e) => {
+            print_failure(
+                &opts,
+                &raw_url,
+                "save",
+                "Failed to save response",
+                &e.to_string(),
+            );
+            return Some(status);
+        }
+    };
+
+    let outcome = Outcome::Saved(&body_filename);
+    print_result(
+        &opts,
+        &shared,
+        &raw_url,
+        status,
+        outcome,
+        &notes,
+        diff.as_deref(),
+    );
+
+    if let Some(ref command) = opts.exec {
+        run_exec(&opts, &shared, command, &body_filename, &raw_url, status).await;
+    }
+
+    Some(status)
+}
+
+/// Run the --exec command for a saved response, forwarding its output
+async fn run_exec(
+    opts: &Opts,
+    shared: &SharedState,
+    command: &str,
+    body_filename: &Path,
+    raw_url: &str,
+    status: StatusCode,
+) {
+    let command = command
+        .replace("{file}", &shell_quote(&body_filename.to_string_lossy()))
+        .replace("{url}", &shell_quote(raw_url))
+        .replace("{status}", status.as_str());
+
+    let _permit = shared.exec_slots.acquire().await.unwrap();
+
+    match shell_command(&command).status().await {
+        Ok(exit) if exit.success() => {}
+        Ok(exit) => print_failure(
+            opts,
+            raw_url,
+            "exec",
+            "Command exited unsuccessfully",
+            &exit.to_string(),
+        ),
+        Err(e) => print_failure(
+            opts,
+            raw_url,
+            "exec",
+            "Failed to run command",
+            &e.to_string(),
+        ),
+    }
+}
+
+fn shell_command(command: &str) -> tokio::process::Command {
+    let (shell, flag) = if cfg!(unix) {
+        ("sh", "-c")
+    } else {
+        ("cmd", "/C")
+    };
+    let mut child = tokio::process::Command::new(shell);
+    child.arg(flag).arg(command);
+    child
+}
+
+/// Quote a value for safe substitution into a shell command
+fn shell_quote(value: &str) -> String {
+    if cfg!(unix) {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    } else {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+}
+
+/// Maximum number of meta refresh redirects followed per URL
+const MAX_META_REFRESHES: usize = 5;
+
+/// Replace the response with the target of its meta refresh, if it has one
+async fn follow_meta_refresh(
+    client: &Client,
+    opts: &Opts,
+    response_data: &mut ResponseData,
+) -> Result<(), reqwest::Error> {
+    for _ in 0..MAX_META_REFRESHES {
+        if !is_html(&response_data.response_body) {
+            break;
+        }
+
+        let target = match extract_meta_refresh(&response_data.response_body)
+            .and_then(|t| response_data.resp_url.join(&t).ok())
+        {
+            Some(t) => t,
+            None => break,
+        };
+
+        let mut req = client.get(target);
+        if let Some(headers) = parse_headers(&opts.header) {
+            req = req.headers(headers);
+        }
+
+        let resp = req.send().await?;
+        response_data.status = resp.status();
+        response_data.version = resp.version();
+        response_data.resp_headers = resp.headers().clone();
+        response_data.resp_url = resp.url().clone();
+        response_data.response_body = resp.bytes().await?;
+    }
+
+    Ok(())
+}
+
+static META_REFRESH_RE: Lazy<regex::bytes::Regex> = Lazy::new(|| {
+    regex::bytes::Regex::new(r#"(?i)<meta\s[^>]*http-equiv\s*=\s*["']?refresh["']?[^>]*>"#).unwrap()
+});
+
+static META_CONTENT_RE: Lazy<regex::bytes::Regex> =
+    Lazy::new(|| regex::bytes::Regex::new(r#"(?i)content\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+/// Extract the URL from a `content="N;url=..."` meta refresh tag
+fn extract_meta_refresh(body: &[u8]) -> Option<String> {
+    let body = &body[..body.len().min(HTML_SCAN_LIMIT)];
+
+    let tag = META_REFRESH_RE.find(body)?.as_bytes();
+    let caps = META_CONTENT_RE.captures(tag)?;
+    let content = caps.get(1).or_else(|| caps.get(2))?.as_bytes();
+    let content = String::from_utf8_lossy(content);
+
+    let (_, target) = content.split_once(';')?;
+    let target = target.trim();
+    if !target.get(..4)?.eq_ignore_ascii_case("url=") {
+        return None;
+    }
+
+    let target = target[4..].trim().trim_matches(|c| c == '\'' || c == '"');
+    if target.is_empty() {
+        None
+    } else {
+        Some(target.to_string())
+    }
+}
+
+static HREF_RE: Lazy<regex::bytes::Regex> = Lazy::new(|| {
+    regex::bytes::Regex::new(r#"(?i)<a\s[^>]*href\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#)
+        .unwrap()
+});
+
+/// Whether a body is a generated directory index, as served by Apache, nginx,
+/// lighttpd or Python's http.server
+fn is_directory_listing(body: &[u8]) -> bool {
+    is_html(body)
+        && extract_title(body).is_some_and(|title| {
+            title.starts_with("Index of ") || title.starts_with("Directory listing for ")
+        })
+}
+
+/// The links in a directory listing that point below it, in page order;
+/// parent directory, column sorting and off-site links are left out
+fn listing_entries(dir: &Url, body: &[u8]) -> Vec<Url> {
+    // A listing served without its trailing slash still only contains its own entries
+    let prefix = match dir.path() {
+        path if path.ends_with('/') => path.to_string(),
+        path => format!("{}/", path),
+    };
+
+    let mut entries: Vec<Url> = Vec::new();
+    for caps in HREF_RE.captures_iter(body) {
+        let href = match caps.get(1).or_else(|| caps.get(2)).or_else(|| caps.get(3)) {
+            Some(href) => String::from_utf8_lossy(href.as_bytes()).replace("&amp;", "&"),
+            None => continue,
+        };
+        let mut entry = match dir.join(&href) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        entry.set_fragment(None);
+
+        let below = entry.origin() == dir.origin()
+            && entry.path().len() > prefix.len()
+            && entry.path().starts_with(&prefix);
+        if below && !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// Render a request the way it goes over the wire, including the headers
+/// the client adds on send: Host, the client's default headers, Accept-Encoding
+/// for the enabled decoders, and Content-Length for fixed bodies
+fn render_request(opts: &Opts, request: &Request) -> String {
+    let url = request.url();
+    let mut headers = HeaderMap::new();
+
+    // A Host set on the request (-H, --host, --sni) is what was sent, so it
+    // replaces the one derived from the URL rather than joining it
+    match request.headers().get(HOST) {
+        Some(host) => {
+            headers.insert(HOST, host.clone());
+        }
+        None => {
+            let mut host = url.host_str().unwrap_or("").to_string();
+            if let Some(port) = url.port() {
+                host.push_str(&format!(":{}", port));
+            }
+            if let Ok(host) = HeaderValue::from_str(&host) {
+                headers.insert(HOST, host);
+            }
+        }
+    }
+
+    for (k, v) in request.headers() {
+        if k != HOST {
+            headers.append(k, v.clone());
+        }
+    }
+
+    if let Some(ref value) = opts.accept_encoding {
+        if !value.is_empty() && !headers.contains_key(ACCEPT_ENCODING) {
+            headers.insert(ACCEPT_ENCODING, value.clone());
+        }
+    }
+
+    if let Some(browser) = opts.impersonate {
+        let mut defaults = browser.default_headers();
+        defaults.insert(USER_AGENT, HeaderValue::from_static(browser.user_agent()));
+        for (k, v) in defaults.iter() {
+            if !headers.contains_key(k) {
+                headers.insert(k, v.clone());
+            }
+        }
+    }
+    if !headers.contains_key(ACCEPT) {
+        headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+    }
+    if auto_decompress(opts) && !headers.contains_key(ACCEPT_ENCODING) {
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, br, deflate"),
+        );
+    }
+
+    let body = request.body().and_then(|b| b.as_bytes());
+    if let Some(body) = body {
+        if !headers.contains_key(CONTENT_LENGTH) {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+    }
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let mut buf = format!("{} {} HTTP/1.1\r\n", request.method(), target);
+    for (k, v) in headers.iter() {
+        buf.push_str(&format!("{}: {}\r\n", k, v.to_str().unwrap_or("")));
+    }
+    buf.push_str("\r\n");
+
+    if let Some(body) = body {
+        buf.push_str(&String::from_utf8_lossy(body));
+    }
+
+    buf
+}
+
+/// Unified diff between two bodies, if both are text
+fn unified_diff(old: &[u8], new: &[u8]) -> Option<String> {
+    let old = std::str::from_utf8(old).ok()?;
+    let new = std::str::from_utf8(new).ok()?;
+
+    Some(
+        TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header("prior", "current")
+            .to_string(),
+    )
+}
+
+/// Summarise a Set-Cookie value as its name and security flags
+fn describe_cookie(set_cookie: &str) -> String {
+    let mut parts = set_cookie.split(';');
+    let name = parts
+        .next()
+        .and_then(|p| p.split_once('=').map(|(name, _)| name))
+        .unwrap_or("")
+        .trim();
+
+    let mut http_only = false;
+    let mut secure = false;
+    let mut same_site = "none";
+
+    for attr in parts {
+        let attr = attr.trim();
+        let (key, value) = attr.split_once('=').unwrap_or((attr, ""));
+        match key.trim().to_ascii_lowercase().as_str() {
+            "httponly" => http_only = true,
+            "secure" => secure = true,
+            "samesite" => same_site = value.trim(),
+            _ => {}
+        }
+    }
+
+    format!(
+        "{} HttpOnly={} Secure={} SameSite={}",
+        name, http_only, secure, same_site
+    )
+}
+
+/// Check the Content-Length header against the configured bounds
+fn content_length_allowed(opts: &Opts, headers: &HeaderMap) -> bool {
+    if opts.min_content_length.is_none() && opts.max_content_length.is_none() {
+        return true;
+    }
+
+    let length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    match length {
+        Some(length) => {
+            opts.min_content_length.is_none_or(|min| length >= min)
+                && opts.max_content_length.is_none_or(|max| length <= max)
+        }
+        None => !opts.exclude_unknown_length,
+    }
+}
+
+/// Read a response body, stopping once it reaches `limit` bytes if one is set;
+/// the flag says whether anything was left unread
+async fn read_body(
+    mut resp: reqwest::Response,
+    limit: Option<usize>,
+) -> reqwest::Result<(Bytes, bool)> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok((resp.bytes().await?, false)),
+    };
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = resp.chunk().await? {
+        let room = limit - body.len();
+        if chunk.len() > room {
+            body.extend_from_slice(&chunk[..room]);
+            return Ok((body.freeze(), true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body.freeze(), false))
+}
+
+/// Broad classes of request failure, for triage
+#[derive(Debug, Clone, Copy)]
+enum RequestErrorKind {
+    Dns,
+    Connect,
+    Timeout,
+    Tls,
+    HeadersTooLarge,
+    ConnectionReset,
+    Other,
+}
+
+impl RequestErrorKind {
+    const ALL: [RequestErrorKind; 7] = [
+        RequestErrorKind::Dns,
+        RequestErrorKind::Connect,
+        RequestErrorKind::Timeout,
+        RequestErrorKind::Tls,
+        RequestErrorKind::HeadersTooLarge,
+        RequestErrorKind::ConnectionReset,
+        RequestErrorKind::Other,
+    ];
+
+    fn of(e: &reqwest::Error) -> Self {
+        let causes = error_causes(e).to_ascii_lowercase();
+
+        if e.is_timeout() {
+            RequestErrorKind::Timeout
+        } else if causes.contains("dns error") || causes.contains("failed to lookup address") {
+            RequestErrorKind::Dns
+        } else if causes.contains("certificate") || causes.contains("tls") {
+            RequestErrorKind::Tls
+        } else if causes.contains("too large")
+            || causes.contains("header list size")
+            || causes.contains("header_list")
+        {
+            RequestErrorKind::HeadersTooLarge
+        } else if causes.contains("incomplete message")
+            || causes.contains("connection closed before message completed")
+            || causes.contains("connection reset")
+        {
+            RequestErrorKind::ConnectionReset
+        } else if e.is_connect() {
+            RequestErrorKind::Connect
+        } else {
+            RequestErrorKind::Other
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            RequestErrorKind::Dns => "dns",
+            RequestErrorKind::Connect => "connect",
+            RequestErrorKind::Timeout => "timeout",
+            RequestErrorKind::Tls => "tls",
+            RequestErrorKind::HeadersTooLarge => "headers",
+            RequestErrorKind::ConnectionReset => "reset",
+            RequestErrorKind::Other => "other",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            RequestErrorKind::Dns => "DNS resolution failed",
+            RequestErrorKind::Connect => "Connection failed",
+            RequestErrorKind::Timeout => "Request timed out",
+            RequestErrorKind::Tls => "TLS handshake failed",
+            RequestErrorKind::HeadersTooLarge => "Response headers too large",
+            RequestErrorKind::ConnectionReset => "Connection closed before a complete response",
+            RequestErrorKind::Other => "Request failed",
+        }
+    }
+}
+
+/// Whether the server hung up before sending a complete response
+fn is_connection_reset(e: &reqwest::Error) -> bool {
+    matches!(RequestErrorKind::of(e), RequestErrorKind::ConnectionReset)
+}
+
+/// All messages in an error's source chain, outermost first
+fn error_causes(e: &reqwest::Error) -> String {
+    let mut causes = Vec::new();
+    let mut source = e.source();
+    while let Some(err) = source {
+        causes.push(err.to_string());
+        source = err.source();
+    }
+    causes.join(": ")
+}
+
+/// Print a categorised request failure and count it for the summary
+fn report_request_error(opts: &Opts, shared: &SharedState, raw_url: &str, e: &reqwest::Error) {
+    let kind = RequestErrorKind::of(e);
+    shared.record_error(kind);
+
+    let causes = error_causes(e);
+    let detail = if causes.is_empty() {
+        e.to_string()
+    } else {
+        causes
+    };
+
+    print_failure(opts, raw_url, kind.label(), kind.message(), &detail);
+}
+
+/// What happened to a response that was received
+enum Outcome<'a> {
+    /// Printed but not saved
+    Seen,
+    /// Saved, with the body at this path
+    Saved(&'a Path),
+    /// Linked to this identical body saved earlier
+    Linked(&'a Path),
+    /// Kept, with the headers and body on the JSON line instead of on disk
+    Inline(&'a ResponseData),
+}
+
+/// Print a URL's result line, as colored text or a JSON object with --json
+fn print_result(
+    opts: &Opts,
+    shared: &SharedState,
+    raw_url: &str,
+    status: StatusCode,
+    outcome: Outcome,
+    notes: &[String],
+    diff: Option<&str>,
+) {
+    if opts.json || shared.json_results.is_some() {
+        let (label, file) = match outcome {
+            Outcome::Seen => ("seen", None),
+            Outcome::Saved(path) => ("saved", Some(path.display().to_string())),
+            Outcome::Linked(path) => ("linked", Some(path.display().to_string())),
+            Outcome::Inline(_) => ("inline", None),
+        };
+        let mut result = json!({
+            "url": raw_url,
+            "status": status.as_u16(),
+            "outcome": label,
+            "file": file,
+            "notes": notes,
+            "diff": diff,
+        });
+        if let Outcome::Inline(response_data) = outcome {
+            result["headers"] = headers_json(&saved_headers(opts, &response_data.resp_headers));
+            result["body_base64"] = json!(
+                base64::engine::general_purpose::STANDARD.encode(&response_data.response_body)
+            );
+        }
+
+        if opts.json {
+            print_output(&format!("{}\n", result));
+        }
+        if let Some(ref results) = shared.json_results {
+            results.lock().unwrap().push(result);
+        }
+
+        // --json replaces the text line; --json-file and --report alone keep it
+        if opts.json {
+            return;
+        }
+    }
+
+    let label = match outcome {
+        Outcome::Seen => colorize_status(status),
+        Outcome::Saved(_) => format!("Saved ({})", status.as_u16()).green(),
+        Outcome::Linked(_) => format!("Linked ({})", status.as_u16()).green(),
+        Outcome::Inline(_) => format!("Kept ({})", status.as_u16()).green(),
+    };
+    let notes: String = notes.iter().map(|n| format!(" [{}]", n)).collect();
+    print_output(&format!("{} {}{}\n", raw_url, label, notes));
+    if let Some(diff) = diff {
+        print_output(diff);
+    }
+}
+
+/// Print a notice about a host to stderr, as yellow text or a JSON object with --json
+fn print_notice(opts: &Opts, host: &str, kind: &str, message: &str) {
+    if opts.json {
+        eprintln!(
+            "{}",
+            json!({ "host": host, "notice": kind, "message": message })
+        );
+    } else {
+        eprintln!("{}", message.yellow());
+    }
+}
+
+/// Print a URL's failure to stderr, as red text or a JSON object with --json
+fn print_failure(opts: &Opts, raw_url: &str, kind: &str, message: &str, detail: &str) {
+    if opts.json {
+        eprintln!(
+            "{}",
+            json!({
+                "url": raw_url,
+                "kind": kind,
+                "error": format!("{}: {}", message, detail),
+            })
+        );
+    } else if kind == "invalid_url" {
+        eprintln!("{}", format!("{}: {}", message, raw_url).red());
+    } else {
+        eprintln!(
+            "{}",
+            format!("{} for {}: {}", message, raw_url, detail).red()
+        );
+    }
+}
+
+/// Function to colorize HTTP status codes
+fn colorize_status(status: StatusCode) -> colored::ColoredString {
+    let status_str = status.as_str();
+
+    match status_tier(status.as_u16()) {
+        "success" => status_str.green(),
+        "redirect" => status_str.cyan(),
+        "client-error" => status_str.yellow(),
+        "server-error" => status_str.red(),
+        _ => status_str.normal(),
+    }
+}
+
+/// The class of a status code, shared by terminal colors and --report's CSS classes
+fn status_tier(status: u16) -> &'static str {
+    match status {
+        200..=299 => "success",
+        300..=399 => "redirect",
+        400..=499 => "client-error",
+        500..=599 => "server-error",
+        _ => "other",
+    }
+}
+
+/// Build the --report page from the collected results, linking each saved
+/// response relative to the output directory
+fn render_report(output_dir: &Path, results: &[serde_json::Value]) -> String {
+    let mut rows = String::new();
+    for result in results {
+        let file = match result["file"].as_str() {
+            Some(file) => Path::new(file),
+            None => continue,
+        };
+        let link = file.strip_prefix(output_dir).unwrap_or(file);
+        let status = result["status"].as_u64().unwrap_or_default() as u16;
+        let notes: Vec<&str> = result["notes"]
+            .as_array()
+            .map(|notes| notes.iter().filter_map(|n| n.as_str()).collect())
+            .unwrap_or_default();
+
+        let _ = writeln!(
+            rows,
+            "<tr><td><span class=\"status {}\">{}</span></td><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            status_tier(status),
+            status,
+            html_escape(&link.to_string_lossy()),
+            html_escape(result["url"].as_str().unwrap_or_default()),
+            html_escape(result["outcome"].as_str().unwrap_or_default()),
+            html_escape(&notes.join(", ")),
+        );
+    }
+
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>fff report</title>
+<style>
+body {{ font-family: sans-serif; }}
+td {{ padding: 2px 8px; }}
+.status {{ font-weight: bold; }}
+.success {{ color: green; }}
+.redirect {{ color: darkcyan; }}
+.client-error {{ color: darkgoldenrod; }}
+.server-error {{ color: red; }}
+</style>
+</head>
+<body>
+<table>
+<tr><th>Status</th><th>URL</th><th>Outcome</th><th>Notes</th></tr>
+{}</table>
+</body>
+</html>
+",
+        rows
+    )
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Whether a raw -H value sets the header `name`
+fn is_header(header: &str, name: HeaderName) -> bool {
+    header
+        .split_once(':')
+        .is_some_and(|(n, _)| n.trim().eq_ignore_ascii_case(name.as_str()))
+}
+
+fn parse_headers(headers: &[String]) -> Option<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    for h in headers {
+        if let Some((name, value)) = h.split_once(':') {
+            let name = name.trim();
+            let value = value.trim();
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                header_map.append(name, value);
+            }
+        }
+    }
+    if header_map.is_empty() {
+        None
+    } else {
+        Some(header_map)
+    }
+}
+
+/// A random (version 4) UUID
+fn random_uuid() -> String {
+    let bits = rand::thread_rng().gen::<u128>() & !(0xf000 << 64) & !(0xc << 60);
+    let bits = bits | (0x4000 << 64) | (0x8 << 60);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Gzip a --body-file as it's streamed, a chunk at a time
+fn gzip_stream(file: tokio_fs::File) -> impl futures::Stream<Item = io::Result<Bytes>> {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    futures::stream::try_unfold((file, Some(encoder)), |(mut file, encoder)| async move {
+        let Some(mut encoder) = encoder else {
+            return Ok(None);
+        };
+        let mut chunk = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut chunk).await?;
+            if n == 0 {
+                let rest = Bytes::from(encoder.finish()?);
+                return Ok(Some((rest, (file, None))));
+            }
+            encoder.write_all(&chunk[..n])?;
+            if !encoder.get_ref().is_empty() {
+                let compressed = Bytes::from(std::mem::take(encoder.get_mut()));
+                return Ok(Some((compressed, (file, Some(encoder)))));
+            }
+        }
+    })
+}
+
+/// Substitute the per-request tokens supported in `--body`
+fn render_body(template: &str, url: &Url) -> String {
+    if !template.contains('{') {
+        return template.to_string();
+    }
+
+    let mut body = template
+        .replace("{url}", url.as_str())
+        .replace("{host}", url.host_str().unwrap_or(""));
+
+    while body.contains("{random}") {
+        let token = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        body = body.replacen("{random}", &token, 1);
+    }
+
+    body
+}
+
+/// Extensions for saved bodies by media type, for --detect-extension
+const CONTENT_TYPE_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/json", "json"),
+    ("application/javascript", "js"),
+    ("application/pdf", "pdf"),
+    ("application/xml", "xml"),
+    ("application/zip", "zip"),
+    ("application/gzip", "gz"),
+    ("application/wasm", "wasm"),
+    ("text/html", "html"),
+    ("text/css", "css"),
+    ("text/csv", "csv"),
+    ("text/javascript", "js"),
+    ("text/xml", "xml"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/svg+xml", "svg"),
+    ("image/x-icon", "ico"),
+    ("image/vnd.microsoft.icon", "ico"),
+    ("font/woff", "woff"),
+    ("font/woff2", "woff2"),
+];
+
+/// Extensions for saved bodies by leading magic bytes, when the Content-Type doesn't say
+const MAGIC_EXTENSIONS: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "png"),
+    (b"\xff\xd8\xff", "jpg"),
+    (b"GIF8", "gif"),
+    (b"%PDF-", "pdf"),
+    (b"PK\x03\x04", "zip"),
+    (b"\x1f\x8b", "gz"),
+    (b"\0asm", "wasm"),
+];
+
+/// Directories for --bucket-by-type, by detected extension; anything else goes under "other"
+const EXTENSION_BUCKETS: &[(&str, &str)] = &[
+    ("png", "images"),
+    ("jpg", "images"),
+    ("gif", "images"),
+    ("webp", "images"),
+    ("svg", "images"),
+    ("ico", "images"),
+    ("json", "json"),
+    ("html", "html"),
+    ("xml", "xml"),
+    ("js", "scripts"),
+    ("css", "styles"),
+    ("pdf", "documents"),
+    ("csv", "documents"),
+    ("zip", "archives"),
+    ("gz", "archives"),
+    ("woff", "fonts"),
+    ("woff2", "fonts"),
+    ("wasm", "wasm"),
+];
+
+/// Extension of the saved body file: "body" unless --detect-extension finds a better one
+fn body_extension(opts: &Opts, response_data: &ResponseData) -> &'static str {
+    if opts.detect_extension {
+        detect_extension(response_data)
+    } else {
+        "body"
+    }
+}
+
+/// Directory a response is bucketed under with --bucket-by-type
+fn type_bucket(response_data: &ResponseData) -> &'static str {
+    let ext = detect_extension(response_data);
+    EXTENSION_BUCKETS
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .map_or("other", |&(_, bucket)| bucket)
+}
+
+/// Extension for a body from its Content-Type, then its magic bytes; "body" if neither says
+fn detect_extension(response_data: &ResponseData) -> &'static str {
+    let media_type = response_data
+        .resp_headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase()
+        });
+    if let Some(media_type) = media_type {
+        if let Some(&(_, ext)) = CONTENT_TYPE_EXTENSIONS
+            .iter()
+            .find(|(t, _)| *t == media_type)
+        {
+            return ext;
+        }
+        // Structured syntax suffixes such as application/ld+json count as their base type
+        if media_type.ends_with("+json") {
+            return "json";
+        }
+        if media_type.ends_with("+xml") {
+            return "xml";
+        }
+    }
+
+    let body = &response_data.response_body;
+    MAGIC_EXTENSIONS
+        .iter()
+        .find(|(magic, _)| body.starts_with(magic))
+        .map_or_else(
+            || if is_html(body) { "html" } else { "body" },
+            |&(_, ext)| ext,
+        )
 }
 
-// Define the ResponseData struct to encapsulate response-related data
-struct ResponseData {
-    method: Method,
-    raw_url: String,
-    response_body: Bytes,
-    resp_headers: HeaderMap,
-    resp_url: Url,
-    status: StatusCode,
-    version: Version,
+fn is_html(body: &[u8]) -> bool {
+    body.windows(5).any(|w| w.eq_ignore_ascii_case(b"<html"))
 }
 
-#[tokio::main]
-async fn main() {
-    let opts = Arc::new(Opts::parse());
-    let client = match new_client(&opts) {
-        Ok(c) => Arc::new(c),
-        Err(e) => {
-            eprintln!("{}", format!("Failed to create HTTP client: {}", e).red());
-            std::process::exit(1);
-        }
-    };
+/// Only the start of a body is searched for HTML elements
+const HTML_SCAN_LIMIT: usize = 64 * 1024;
 
-    let semaphore = Arc::new(Semaphore::new(100)); // Limit concurrency to 100
-    let mut tasks = FuturesUnordered::new();
+/// Extract the text of the first <title> element, if any
+fn extract_title(body: &[u8]) -> Option<String> {
+    let body = &body[..body.len().min(HTML_SCAN_LIMIT)];
+
+    let open = find_ignore_ascii_case(body, b"<title")?;
+    let start = open + body[open..].iter().position(|&b| b == b'>')? + 1;
+    let end = start + find_ignore_ascii_case(&body[start..], b"</title")?;
 
-    let stdin = tokio_io::stdin();
-    let reader = tokio_io::BufReader::new(stdin);
-    let mut lines = reader.lines();
+    let title = String::from_utf8_lossy(&body[start..end])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
 
-    while let Some(line) = lines.next_line().await.unwrap_or_else(|e| {
-        eprintln!("{}", format!("Error reading line from stdin: {}", e).red());
+    if title.is_empty() {
         None
-    }) {
-        let url = line;
-        let permit = semaphore.clone().acquire_owned().await.unwrap();
-        let client = Arc::clone(&client);
-        let opts = Arc::clone(&opts);
-
-        tasks.push(tokio::spawn(async move {
-            if opts.delay > 0 {
-                sleep(Duration::from_millis(opts.delay)).await;
-            }
-            process_url(client, opts, url).await;
-            drop(permit);
-        }));
+    } else {
+        Some(title)
+    }
+}
 
-        while tasks.len() >= 100 {
-            tasks.next().await;
+/// The visible text of an HTML document: tags, comments, scripts and styles
+/// are dropped, common entities decoded and whitespace collapsed line by line
+fn html_to_text(body: &[u8]) -> String {
+    let html = String::from_utf8_lossy(body);
+    let lower = html.to_ascii_lowercase();
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut pos = 0;
+
+    while let Some(offset) = html[pos..].find('<') {
+        text.push_str(&html[pos..pos + offset]);
+        let tag = pos + offset;
+
+        // Skip the contents of elements that are never shown
+        let skip_to = if lower[tag..].starts_with("<!--") {
+            lower[tag..].find("-->").map(|end| tag + end + 3)
+        } else if lower[tag..].starts_with("<script") {
+            lower[tag..].find("</script").map(|end| tag + end)
+        } else if lower[tag..].starts_with("<style") {
+            lower[tag..].find("</style").map(|end| tag + end)
+        } else {
+            None
+        };
+        let from = skip_to.unwrap_or(tag);
+
+        match html[from..].find('>') {
+            Some(end) => {
+                // Keep words in adjacent elements apart
+                text.push(' ');
+                pos = from + end + 1;
+            }
+            None => {
+                pos = html.len();
+                break;
+            }
         }
     }
+    text.push_str(&html[pos..]);
 
-    while tasks.next().await.is_some() {}
-}
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
 
-fn new_client(opts: &Opts) -> Result<Client, reqwest::Error> {
-    let mut builder = Client::builder()
-        .timeout(Duration::from_secs(10))
-        .danger_accept_invalid_certs(true);
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
 
-    if !opts.keep_alive {
-        builder = builder.pool_idle_timeout(Duration::from_secs(0));
-    }
+/// Summary of a DER certificate followed by the certificate as PEM
+fn describe_certificate(der: &[u8]) -> String {
+    let mut out =
+        parse_certificate(der).unwrap_or_else(|| "Certificate could not be parsed\n".to_string());
 
-    if let Some(ref proxy_url) = opts.proxy {
-        builder = builder.proxy(Proxy::all(proxy_url)?);
+    out.push_str("\n-----BEGIN CERTIFICATE-----\n");
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+    for line in encoded.as_bytes().chunks(64) {
+        out.push_str(&String::from_utf8_lossy(line));
+        out.push('\n');
     }
-
-    builder.build()
+    out.push_str("-----END CERTIFICATE-----\n");
+    out
 }
 
-async fn process_url(client: Arc<Client>, opts: Arc<Opts>, raw_url: String) {
-    let mut method = opts.method.clone();
-    let request_body = opts.body.clone();
+/// Subject, issuer, serial, validity and SANs of an X.509 certificate
+fn parse_certificate(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
 
-    if request_body.is_some() && method.eq_ignore_ascii_case("GET") {
-        method = "POST".to_string();
+    let sans: Vec<String> = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|ext| {
+            ext.value
+                .general_names
+                .iter()
+                .filter_map(|name| match *name {
+                    GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+                    GeneralName::IPAddress(ip) => {
+                        let ip = match ip.len() {
+                            4 => <[u8; 4]>::try_from(ip).ok().map(IpAddr::from),
+                            16 => <[u8; 16]>::try_from(ip).ok().map(IpAddr::from),
+                            _ => None,
+                        };
+                        ip.map(|ip| format!("IP:{}", ip))
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    out.push_str(&format!("Subject: {}\n", name_summary(cert.subject())));
+    out.push_str(&format!("Issuer: {}\n", name_summary(cert.issuer())));
+    let serial: String = cert
+        .raw_serial()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    out.push_str(&format!("Serial: {}\n", serial));
+    let validity = cert.validity();
+    out.push_str(&format!(
+        "Not before: {}\n",
+        cert_time(&validity.not_before)?
+    ));
+    out.push_str(&format!("Not after: {}\n", cert_time(&validity.not_after)?));
+    if !sans.is_empty() {
+        out.push_str(&format!("SAN: {}\n", sans.join(", ")));
     }
+    Some(out)
+}
 
-    let url = match Url::parse(&raw_url) {
-        Ok(u) => u,
-        Err(_) => {
-            eprintln!("{}", format!("Invalid URL: {}", raw_url).red());
-            return;
-        }
+/// A distinguished name as `CN=..., O=...`, keeping the attributes we know
+fn name_summary(name: &X509Name) -> String {
+    use x509_parser::oid_registry::{
+        OID_X509_COMMON_NAME, OID_X509_COUNTRY_NAME, OID_X509_LOCALITY_NAME,
+        OID_X509_ORGANIZATIONAL_UNIT, OID_X509_ORGANIZATION_NAME, OID_X509_STATE_OR_PROVINCE_NAME,
     };
 
-    let method = method.parse::<Method>().unwrap_or(Method::GET);
+    let mut parts = Vec::new();
+    for attribute in name.iter_rdn().flat_map(|rdn| rdn.iter()) {
+        let oid = attribute.attr_type();
+        let label = if *oid == OID_X509_COMMON_NAME {
+            "CN"
+        } else if *oid == OID_X509_COUNTRY_NAME {
+            "C"
+        } else if *oid == OID_X509_LOCALITY_NAME {
+            "L"
+        } else if *oid == OID_X509_STATE_OR_PROVINCE_NAME {
+            "ST"
+        } else if *oid == OID_X509_ORGANIZATION_NAME {
+            "O"
+        } else if *oid == OID_X509_ORGANIZATIONAL_UNIT {
+            "OU"
+        } else {
+            continue;
+        };
+        let value = String::from_utf8_lossy(attribute.attr_value().as_bytes());
+        parts.push(format!("{}={}", label, value));
+    }
+    parts.join(", ")
+}
 
-    let mut req = client.request(method.clone(), url.clone());
+/// A certificate validity time as `YYYY-MM-DD HH:MM:SS UTC`
+fn cert_time(time: &ASN1Time) -> Option<String> {
+    let time = chrono::DateTime::from_timestamp(time.timestamp(), 0)?;
+    Some(time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+}
 
-    // Add headers
-    if let Some(headers) = parse_headers(&opts.header) {
-        req = req.headers(headers);
-    }
+/// Where a WAF leaves its mark on a response
+enum WafMarker {
+    /// A header that's present at all
+    Header(&'static str),
+    /// A header whose value contains a string, ignoring case
+    HeaderValue(&'static str, &'static str),
+    /// A string in the start of the body, ignoring case
+    Body(&'static str),
+}
 
-    // Add body
-    if let Some(body) = request_body.clone() {
-        req = req.body(body);
-    }
+/// Known WAF signatures, checked in order
+const WAF_SIGNATURES: &[(&str, WafMarker)] = &[
+    ("cloudflare", WafMarker::Header("cf-ray")),
+    ("cloudflare", WafMarker::HeaderValue("server", "cloudflare")),
+    ("akamai", WafMarker::HeaderValue("server", "akamaighost")),
+    ("akamai", WafMarker::Header("akamai-grn")),
+    ("imperva", WafMarker::Header("x-iinfo")),
+    ("imperva", WafMarker::HeaderValue("x-cdn", "incapsula")),
+    ("imperva", WafMarker::HeaderValue("set-cookie", "incap_ses")),
+    ("sucuri", WafMarker::Header("x-sucuri-id")),
+    (
+        "f5-bigip",
+        WafMarker::HeaderValue("set-cookie", "bigipserver"),
+    ),
+    ("aws-waf", WafMarker::Header("x-amzn-waf-action")),
+    (
+        "modsecurity",
+        WafMarker::HeaderValue("server", "mod_security"),
+    ),
+    ("modsecurity", WafMarker::Body("mod_security")),
+    ("modsecurity", WafMarker::Body("modsecurity")),
+];
 
-    // Send the request
-    let resp = match req.send().await {
-        Ok(r) => r,
-        Err(e) => {
-            eprintln!("{}", format!("Request failed for {}: {}", raw_url, e).red());
-            return;
-        }
-    };
+/// Name of the first WAF whose signature matches the response
+fn detect_waf(headers: &HeaderMap, body: &[u8]) -> Option<&'static str> {
+    let body = &body[..body.len().min(HTML_SCAN_LIMIT)];
 
-    // Extract response data
-    let status = resp.status();
-    let version = resp.version();
-    let resp_headers = resp.headers().clone();
-    let resp_url = resp.url().clone();
-    let response_body = match resp.bytes().await {
-        Ok(b) => b,
-        Err(e) => {
-            eprintln!(
-                "{}",
-                format!("Failed to read body for {}: {}", raw_url, e).red()
-            );
-            return;
-        }
-    };
+    WAF_SIGNATURES
+        .iter()
+        .find(|(_, marker)| match *marker {
+            WafMarker::Header(name) => headers.contains_key(name),
+            WafMarker::HeaderValue(name, needle) => headers
+                .get_all(name)
+                .iter()
+                .any(|v| find_ignore_ascii_case(v.as_bytes(), needle.as_bytes()).is_some()),
+            WafMarker::Body(needle) => find_ignore_ascii_case(body, needle.as_bytes()).is_some(),
+        })
+        .map(|&(name, _)| name)
+}
 
-    // Create ResponseData instance
-    let response_data = ResponseData {
-        method: method.clone(),
-        raw_url: raw_url.clone(),
-        response_body,
-        resp_headers,
-        resp_url,
-        status,
-        version,
-    };
+fn find_ignore_ascii_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+}
 
-    let mut should_save =
-        opts.save || (!opts.save_status.is_empty() && opts.save_status.contains(&status.as_u16()));
+/// Directory, relative to the output root, and file stem a response is saved under
+fn response_location(opts: &Opts, response_data: &ResponseData) -> (PathBuf, String) {
+    let resp_url = &response_data.resp_url;
+    let normalised_path = shorten_components(&normalise_path(resp_url), opts.max_name_len.into());
 
-    // Check if response is HTML
-    if opts.ignore_html && is_html(&response_data.response_body) {
-        should_save = false;
-    }
+    let hash = name_hash(&request_key(opts, response_data), u64::MAX);
+    let mut hash_hex = format!("{:016x}{}", hash, response_data.name_suffix);
 
-    // Check if response body is empty or whitespace
-    if opts.ignore_empty
-        && response_data
-            .response_body
-            .iter()
-            .all(|&b| b.is_ascii_whitespace())
-    {
-        should_save = false;
+    // Number repeated requests so each attempt is kept
+    if opts.repeat.get() > 1 {
+        hash_hex = format!("{}.{}", hash_hex, response_data.attempt);
     }
 
-    // Check if response body contains the match string
-    if let Some(ref m) = opts.r#match {
-        should_save = twoway::find_bytes(&response_data.response_body, m.as_bytes()).is_some();
+    // Tag files with the method so captures of the same URL are easy to pair
+    if opts.both_methods || opts.methods_file.is_some() {
+        hash_hex = format!(
+            "{}.{}",
+            hash_hex,
+            response_data.method.as_str().to_lowercase()
+        );
     }
 
-    if !should_save {
-        println!("{} {}", raw_url, colorize_status(status));
-        return;
+    if opts.flat {
+        return (PathBuf::new(), hash_hex);
     }
 
-    if let Err(e) = save_response(&opts, &response_data).await {
-        eprintln!(
-            "{}",
-            format!("Failed to save response for {}: {}", raw_url, e).red()
-        );
-    } else {
-        println!(
-            "{} {}",
-            raw_url,
-            format!("Saved ({})", status.as_u16()).green()
-        );
+    let mut response_dir = PathBuf::new();
+    if opts.bucket_by_type {
+        response_dir.push(type_bucket(response_data));
     }
+    response_dir.push(host_dir(opts, response_data));
+    if opts.http_version_dir {
+        response_dir.push(format!("http{}", version_str(response_data.version)));
+    }
+    response_dir.push(normalised_path);
+
+    (response_dir, hash_hex)
 }
 
-/// Function to colorize HTTP status codes
-fn colorize_status(status: StatusCode) -> colored::ColoredString {
-    let status_code = status.as_u16();
-    let status_str = status.as_str();
+/// Hash of a request key for its file name, keeping only the bits in `mask`
+fn name_hash(key: &str, mask: u64) -> u64 {
+    // Use xxHash instead of SHA1
+    let mut hasher = Xxh3::new();
+    hasher.update(key.as_bytes());
+    hasher.digest() & mask
+}
 
-    match status_code {
-        200..=299 => status_str.green(),
-        300..=399 => status_str.cyan(),
-        400..=499 => status_str.yellow(),
-        500..=599 => status_str.red(),
-        _ => status_str.normal(),
-    }
+/// What tells requests apart when naming their files: the method, URL,
+/// headers and the body as it was sent, tokens and all
+fn request_key(opts: &Opts, response_data: &ResponseData) -> String {
+    format!(
+        "{}{}{}{}",
+        response_data.method,
+        response_data.raw_url,
+        response_data.request_body.as_deref().unwrap_or_default(),
+        opts.header.join("")
+    )
 }
 
-fn parse_headers(headers: &[String]) -> Option<HeaderMap> {
-    let mut header_map = HeaderMap::new();
-    for h in headers {
-        if let Some((name, value)) = h.split_once(':') {
-            let name = name.trim();
-            let value = value.trim();
-            if let (Ok(name), Ok(value)) = (
-                HeaderName::from_bytes(name.as_bytes()),
-                HeaderValue::from_str(value),
-            ) {
-                header_map.append(name, value);
+/// Name of the per-host directory a response is saved under
+fn host_dir(opts: &Opts, response_data: &ResponseData) -> String {
+    let url = &response_data.resp_url;
+    let parsed = url.host_str().unwrap_or("unknown");
+
+    // The URL parser lowercases http(s) hosts, so the input's spelling is
+    // taken back from the line, unless a redirect led to another host
+    let host = if opts.preserve_host_case {
+        input_host(&response_data.raw_url)
+            .filter(|host| host.eq_ignore_ascii_case(parsed))
+            .unwrap_or(parsed)
+            .to_string()
+    } else {
+        parsed.to_ascii_lowercase()
+    };
+
+    match opts.host_dir {
+        HostDir::Host => host,
+        HostDir::HostPort => {
+            // Colons aren't allowed in file names everywhere, so IPv6 hosts keep
+            // their brackets but swap colons for dashes
+            let host = host.replace(':', "-");
+            match url.port_or_known_default() {
+                Some(port) => format!("{}_{}", host, port),
+                None => host,
             }
         }
     }
-    if header_map.is_empty() {
-        None
-    } else {
-        Some(header_map)
-    }
 }
 
-fn is_html(body: &[u8]) -> bool {
-    body.windows(5).any(|w| w.eq_ignore_ascii_case(b"<html"))
+/// The host of an input URL as written, before the URL parser normalises it
+fn input_host(raw_url: &str) -> Option<&str> {
+    let (_, rest) = raw_url.trim().split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    // IPv6 hosts keep their brackets, as in the parsed URL
+    if host.starts_with('[') {
+        host.split_inclusive(']').next()
+    } else {
+        host.split(':').next()
+    }
 }
 
-async fn save_response(opts: &Opts, response_data: &ResponseData) -> io::Result<()> {
+async fn save_response(
+    opts: &Opts,
+    shared: &SharedState,
+    response_data: &ResponseData,
+) -> io::Result<PathBuf> {
     let method = &response_data.method;
     let raw_url = &response_data.raw_url;
     let response_body = &response_data.response_body;
-    let resp_headers = &response_data.resp_headers;
-    let resp_url = &response_data.resp_url;
+    let resp_headers = &saved_headers(opts, &response_data.resp_headers);
     let status = response_data.status;
     let version = response_data.version;
 
-    let normalised_path = normalise_path(resp_url);
-
-    let hash_input = format!(
-        "{}{}{}{}",
-        method,
-        raw_url,
-        opts.body.clone().unwrap_or_default(),
-        opts.header.join("")
-    );
+    let (response_dir, hash_hex) = response_location(opts, response_data);
+    let output_dir = shared.output_dir.join(response_dir);
 
-    // Use xxHash instead of SHA1
-    let mut hasher = Xxh3::new();
-    hasher.update(hash_input.as_bytes());
-    let hash = hasher.digest();
-    let hash_hex = format!("{:016x}", hash);
+    tokio_fs::create_dir_all(&output_dir).await?;
 
-    let host = resp_url.host_str().unwrap_or("unknown");
-    let output_dir = opts.output.join(host).join(normalised_path);
+    // Endpoints that answer with nothing can be catalogued by their headers alone
+    let skip_body = opts.drop_body
+        || (opts.headers_only_when_empty && is_blank(response_body))
+        || (opts.both_methods && response_data.method == Method::HEAD);
 
-    tokio_fs::create_dir_all(&output_dir).await?;
+    let body_filename = output_dir.join(format!(
+        "{}.{}",
+        hash_hex,
+        body_extension(opts, response_data)
+    ));
+    let saved_body = match opts.save_bytes {
+        Some(n) => &response_body[..n.min(response_body.len())],
+        None => &response_body[..],
+    };
+    if !skip_body {
+        write_file(opts, &body_filename, saved_body).await?;
+    }
 
-    let body_filename = output_dir.join(format!("{}.body", hash_hex));
-    tokio_fs::write(&body_filename, response_body).await?;
+    if opts.extract_text && is_html(response_body) {
+        let text_filename = output_dir.join(format!("{}.txt", hash_hex));
+        write_file(opts, &text_filename, html_to_text(response_body)).await?;
+    }
 
     let headers_filename = output_dir.join(format!("{}.headers", hash_hex));
     let mut buf = String::with_capacity(1024);
@@ -337,20 +5024,25 @@ async fn save_response(opts: &Opts, response_data: &ResponseData) -> io::Result<
     buf.push('\n');
 
     // Request body
-    if let Some(body) = &opts.body {
+    if let Some(body) = &response_data.request_body {
         buf.push_str(body);
         buf.push_str("\n\n");
     }
 
+    // Redirects followed on the way
+    for hop in &response_data.redirects {
+        buf.push_str(&format!(
+            "* Redirect: {} {}\n",
+            hop.status.as_u16(),
+            hop.location
+        ));
+    }
+    if !response_data.redirects.is_empty() {
+        buf.push('\n');
+    }
+
     // Status line
-    let version_str = match version {
-        Version::HTTP_09 => "0.9",
-        Version::HTTP_10 => "1.0",
-        Version::HTTP_11 => "1.1",
-        Version::HTTP_2 => "2",
-        Version::HTTP_3 => "3",
-        _ => "unknown",
-    };
+    let version_str = version_str(version);
 
     buf.push_str(&format!(
         "< HTTP/{} {} {}\n",
@@ -363,14 +5055,312 @@ async fn save_response(opts: &Opts, response_data: &ResponseData) -> io::Result<
     for (k, v) in resp_headers.iter() {
         buf.push_str(&format!("< {}: {}\n", k, v.to_str().unwrap_or("")));
     }
+    if opts.save_bytes.is_some_and(|n| n < response_body.len()) {
+        buf.push_str(&format!("< X-FFF-Body-Length: {}\n", response_body.len()));
+    }
+    if let Some(charset) = response_data.original_charset {
+        buf.push_str(&format!("< X-FFF-Original-Charset: {}\n", charset));
+    }
+    // The hash covers what's saved, so it can be checked against the body file
+    if opts.body_hash_in_headers {
+        buf.push_str(&format!(
+            "< X-FFF-Body-XXH3: {:016x}\n",
+            xxh3_64(saved_body)
+        ));
+    }
+
+    if let Some(proxy) = &response_data.proxy {
+        buf.push_str(&format!("\n* Proxy: {}\n", proxy));
+    }
+
+    write_file(opts, &headers_filename, buf).await?;
+
+    if let Some(raw_request) = &response_data.raw_request {
+        let request_filename = output_dir.join(format!("{}.request", hash_hex));
+        write_file(opts, &request_filename, raw_request).await?;
+    }
+
+    if opts.save_cert && response_data.resp_url.scheme() == "https" {
+        let cert_filename = output_dir.join(format!("{}.cert", hash_hex));
+        let cert = match response_data.peer_cert {
+            Some(ref der) => describe_certificate(der),
+            None => "Certificate unavailable\n".to_string(),
+        };
+        write_file(opts, &cert_filename, cert).await?;
+    }
+
+    if opts.meta_json {
+        let meta_filename = output_dir.join(format!("{}.json", hash_hex));
+        let meta = response_metadata(opts, response_data);
+        write_file(opts, &meta_filename, meta.to_string()).await?;
+    }
+
+    if let Some(ref manifest) = shared.manifest {
+        manifest.write_line(&format!("{}\t{}\t{}", hash_hex, status.as_u16(), raw_url));
+    }
+
+    if skip_body {
+        Ok(headers_filename)
+    } else {
+        Ok(body_filename)
+    }
+}
+
+/// Count and report a status outside the --expect ranges
+fn check_expected(opts: &Opts, shared: &SharedState, raw_url: &str, status: StatusCode) {
+    if !opts.expect.is_empty() && !opts.expect.iter().any(|r| r.contains(status.as_u16())) {
+        shared.unexpected.fetch_add(1, Ordering::Relaxed);
+        let expected: Vec<String> = opts.expect.iter().map(ToString::to_string).collect();
+        print_failure(
+            opts,
+            raw_url,
+            "unexpected_status",
+            "Unexpected status",
+            &format!("{} (expected {})", status.as_u16(), expected.join(", ")),
+        );
+    }
+}
+
+/// The form of a body used for comparisons: with --canonical-json, JSON
+/// bodies are re-serialized with sorted keys and no insignificant whitespace
+fn canonical_body<'a>(opts: &Opts, body: &'a [u8]) -> Cow<'a, [u8]> {
+    if opts.canonical_json {
+        // serde_json's maps are ordered by key, so serializing sorts them
+        if let Ok(value) = serde_json::from_slice::<serde_json::Value>(body) {
+            if let Ok(canonical) = serde_json::to_vec(&value) {
+                return Cow::Owned(canonical);
+            }
+        }
+    }
+    Cow::Borrowed(body)
+}
+
+/// Transcode a body to UTF-8 from the encoding named by its BOM or Content-Type
+/// charset, returning it with the encoding's name; None if it's already UTF-8
+/// or the encoding is unknown
+fn decode_charset(headers: &HeaderMap, body: &[u8]) -> Option<(Bytes, &'static str)> {
+    let encoding = match Encoding::for_bom(body) {
+        Some((encoding, _)) => encoding,
+        None => {
+            let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+            let label = content_type.split(';').skip(1).find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("charset")
+                    .then(|| value.trim().trim_matches('"'))
+            })?;
+            Encoding::for_label(label.as_bytes())?
+        }
+    };
+
+    if encoding == UTF_8 {
+        return None;
+    }
+
+    // decode() sniffs and strips the BOM itself, replacing malformed sequences
+    let (decoded, encoding, _) = encoding.decode(body);
+    Some((Bytes::from(decoded.into_owned()), encoding.name()))
+}
+
+/// Whether a body is empty or only whitespace
+fn is_blank(body: &[u8]) -> bool {
+    body.iter().all(|&b| b.is_ascii_whitespace())
+}
+
+fn version_str(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_11 => "1.1",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "unknown",
+    }
+}
+
+/// Structured metadata for the --meta-json sidecar
+fn response_metadata(opts: &Opts, response_data: &ResponseData) -> serde_json::Value {
+    let request_headers = parse_headers(&opts.header).unwrap_or_default();
+
+    json!({
+        "request": {
+            "method": response_data.method.as_str(),
+            "url": response_data.raw_url,
+            "headers": headers_json(&request_headers),
+            "body_size": response_data.request_body.as_ref().map_or(0, |b| b.len()),
+        },
+        "response": {
+            "status": response_data.status.as_u16(),
+            "version": format!("HTTP/{}", version_str(response_data.version)),
+            "url": response_data.resp_url.as_str(),
+            "headers": headers_json(&saved_headers(opts, &response_data.resp_headers)),
+            "body_size": response_data.response_body.len(),
+        },
+        "redirects": response_data
+            .redirects
+            .iter()
+            .map(|hop| json!({"status": hop.status.as_u16(), "location": hop.location.as_str()}))
+            .collect::<Vec<_>>(),
+        "proxy": response_data.proxy,
+        "elapsed_ms": response_data.elapsed.as_millis() as u64,
+    })
+}
+
+/// Response headers as they should be saved, after --keep-header and --strip-header
+fn saved_headers(opts: &Opts, headers: &HeaderMap) -> HeaderMap {
+    if opts.keep_header.is_empty() && opts.strip_header.is_empty() {
+        return headers.clone();
+    }
+
+    let mut kept = HeaderMap::new();
+    for (name, value) in headers {
+        if (opts.keep_header.is_empty() || opts.keep_header.contains(name))
+            && !opts.strip_header.contains(name)
+        {
+            kept.append(name, value.clone());
+        }
+    }
+    kept
+}
+
+/// Headers as an object of names to lists of values
+fn headers_json(headers: &HeaderMap) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for name in headers.keys() {
+        let values = headers
+            .get_all(name)
+            .iter()
+            .map(|v| json!(String::from_utf8_lossy(v.as_bytes())))
+            .collect();
+        map.insert(name.to_string(), serde_json::Value::Array(values));
+    }
+    serde_json::Value::Object(map)
+}
+
+/// Symlink `link` to `original`, both relative to the output directory, with a
+/// relative target so the output directory can be moved
+#[cfg(unix)]
+async fn link_duplicate(output_dir: &Path, link: &Path, original: &Path) -> io::Result<()> {
+    let link_dir = link.parent().unwrap_or(Path::new(""));
+    tokio_fs::create_dir_all(output_dir.join(link_dir)).await?;
+
+    let mut target = PathBuf::new();
+    for _ in link_dir.components() {
+        target.push("..");
+    }
+    target.push(original);
+
+    let link = output_dir.join(link);
+    let _ = tokio_fs::remove_file(&link).await;
+    tokio_fs::symlink(target, link).await
+}
+
+#[cfg(not(unix))]
+async fn link_duplicate(_output_dir: &Path, _link: &Path, _original: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "symlinks are only supported on Unix",
+    ))
+}
+
+/// Write a saved file, applying the --chmod mode if one was given
+async fn write_file(opts: &Opts, path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut options = tokio_fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    // Create the file with its mode, so it's never readable more widely first
+    #[cfg(unix)]
+    if let Some(mode) = opts.chmod {
+        options.mode(mode);
+    }
+
+    let mut file = options.open(path).await?;
+
+    // The creation mode is narrowed by the umask and not applied to existing
+    // files, so set it exactly before writing anything
+    #[cfg(unix)]
+    if let Some(mode) = opts.chmod {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .await?;
+    }
 
-    tokio_fs::write(&headers_filename, buf).await?;
+    file.write_all(contents.as_ref()).await?;
+    file.flush().await?;
+    if opts.fsync {
+        file.sync_all().await?;
+    }
 
     Ok(())
 }
 
+/// A --rate above 0 whose interval, doubled by the widest jitter, can still
+/// be scheduled
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|_| format!("invalid rate: {}", s))?;
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err(format!("rate must be above 0: {}", s));
+    }
+
+    Duration::try_from_secs_f64(2.0 / rate)
+        .ok()
+        .and_then(|interval| Instant::now().checked_add(interval))
+        .map(|_| rate)
+        .ok_or_else(|| format!("rate is too low: {}", s))
+}
+
+/// Open a file in the output directory for appending, with the --chmod mode
+/// from the moment it's created, as write_file does
+fn append_saved(opts: &Opts, path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+
+    #[cfg(unix)]
+    if let Some(mode) = opts.chmod {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+
+    let file = options.open(path)?;
+
+    // The creation mode is narrowed by the umask and not applied to existing files
+    #[cfg(unix)]
+    if let Some(mode) = opts.chmod {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = opts;
+
+    Ok(file)
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+        .ok_or_else(|| format!("invalid octal mode: {}", s))
+}
+
 static PATH_NORMALISE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[^a-zA-Z0-9/._-]+").unwrap());
 
+/// Cut path components longer than `max_len` bytes, appending a hash of the
+/// full component so distinct long names stay distinct
+fn shorten_components(path: &str, max_len: usize) -> String {
+    path.split('/')
+        .map(|component| {
+            if component.len() <= max_len {
+                return component.to_string();
+            }
+            // Normalised paths are ASCII, so any byte offset is a char boundary
+            let hash = format!("{:016x}", xxh3_64(component.as_bytes()));
+            format!("{}-{}", &component[..max_len - hash.len() - 1], hash)
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 fn normalise_path(url: &Url) -> String {
     let path = url.path();
     let normalised = PATH_NORMALISE_RE.replace_all(path, "-").to_string();
@@ -381,3 +5371,35 @@ fn normalise_path(url: &Url) -> String {
         normalised
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_name_on_collision() {
+        // With no hash bits kept, two different requests share one file name
+        let (first, second) = (
+            "POST http://localhost/submit id=1",
+            "POST http://localhost/submit id=2",
+        );
+        assert_eq!(name_hash(first, 0), name_hash(second, 0));
+        let base = PathBuf::from(format!("{:016x}", name_hash(first, 0)));
+        let (first, second) = (xxh3_64(first.as_bytes()), xxh3_64(second.as_bytes()));
+
+        for (policy, expected) in [
+            ("overwrite", Some("")),
+            ("suffix", Some("-1")),
+            ("skip", None),
+        ] {
+            let opts = Opts::parse_from(["fff", "--on-collision", policy]);
+            let shared = SharedState::new(&opts).unwrap();
+
+            // The first request keeps the bare name, also when it comes back
+            assert_eq!(shared.claim_name(&opts, &base, first).as_deref(), Some(""));
+            assert_eq!(shared.claim_name(&opts, &base, second).as_deref(), expected);
+            assert_eq!(shared.claim_name(&opts, &base, first).as_deref(), Some(""));
+            assert_eq!(shared.claim_name(&opts, &base, second).as_deref(), expected);
+        }
+    }
+}
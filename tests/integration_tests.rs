@@ -5,6 +5,10 @@ use httpmock::prelude::*;
 use predicates::prelude::*;
 use regex::Regex;
 use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tempfile::TempDir;
 
@@ -23,6 +27,82 @@ fn normalise_path(url: &reqwest::Url) -> String {
     }
 }
 
+// Serve raw HTTP responses in order, repeating the last one, and count requests
+fn scripted_server(responses: Vec<&'static str>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    scripted_server_on(listener, responses)
+}
+
+fn scripted_server_on(
+    listener: std::net::TcpListener,
+    responses: Vec<&'static str>,
+) -> (SocketAddr, Arc<AtomicUsize>) {
+    use std::io::{Read, Write};
+
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let counter = Arc::clone(&requests);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 8192];
+            let _ = stream.read(&mut buf);
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            let response = responses[n.min(responses.len() - 1)];
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+
+    (addr, requests)
+}
+
+/// Serve HTTP/2 over TLS, negotiated with ALPN, answering every request with
+/// `response()` and `body`
+async fn h2_server(response: fn() -> http::Response<()>, body: &'static [u8]) -> SocketAddr {
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let cert = CertificateDer::from_pem_slice(include_bytes!("fixtures/test-cert.pem")).unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("fixtures/test-key.pem")).unwrap();
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(vec![cert], key)
+    .unwrap();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let tls = match acceptor.accept(stream).await {
+                    Ok(tls) => tls,
+                    Err(_) => return,
+                };
+                let mut conn = match h2::server::handshake(tls).await {
+                    Ok(conn) => conn,
+                    Err(_) => return,
+                };
+                while let Some(Ok((_, mut respond))) = conn.accept().await {
+                    if let Ok(mut stream) = respond.send_response(response(), false) {
+                        let _ = stream.send_data(bytes::Bytes::from_static(body), true);
+                    }
+                }
+            });
+        }
+    });
+
+    addr
+}
+
 #[tokio::test]
 async fn test_basic_request() {
     // Start a mock server
@@ -466,3 +546,5441 @@ async fn test_proxy_option() {
     let hits = _proxy_mock.hits();
     assert!(hits > 0, "Proxy server was not used");
 }
+
+#[tokio::test]
+async fn test_body_template_tokens() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Only match when the {host} token was substituted into the body
+    let host = server.address().ip().to_string();
+    let expected_body = format!("target={}", host);
+
+    let _mock = server.mock(|when, then| {
+        when.method(POST).path("/fuzz").body(&expected_body);
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Set a templated body
+    cmd.arg("-b").arg("target={host}");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/fuzz")));
+
+    // Run the command and capture output
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200"));
+
+    // Verify that the mock received the substituted body
+    assert_eq!(_mock.hits(), 1, "The templated body was not substituted");
+}
+
+#[tokio::test]
+async fn test_skip_status() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Create one response to keep and one to skip
+    let _ok = server.mock(|when, then| {
+        when.method(GET).path("/ok");
+        then.status(200).body("Found");
+    });
+
+    let _missing = server.mock(|when, then| {
+        when.method(GET).path("/missing");
+        then.status(404).body("Not Found");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save everything except 404s
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--skip-status")
+            .arg("404");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/ok"),
+            server.url("/missing")
+        ));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved (200)"))
+            .stdout(predicate::str::contains("Saved (404)").not());
+    }
+
+    // Verify that only the 200 response was saved
+    let host = server.address().ip().to_string();
+    assert!(temp_dir.path().join(&host).join("ok").is_dir());
+    assert!(
+        !temp_dir.path().join(&host).join("missing").exists(),
+        "Skipped status should not be saved"
+    );
+}
+
+#[tokio::test]
+async fn test_titles() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Create a mock HTML response with a title
+    let html_body = "<html><head><TITLE>\n  Login Page\n</TITLE></head><body>Test</body></html>";
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .header("Content-Type", "text/html")
+            .body(html_body);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Print titles
+    cmd.arg("--titles");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and capture output
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("[Login Page]"));
+}
+
+#[tokio::test]
+async fn test_save_request() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("Hello, world!");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save the request alongside the response
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--save-request")
+            .arg("-H")
+            .arg("X-Test-Header: HeaderValue");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that the request file was created
+    let host = server.address().ip().to_string();
+    let expected_dir = temp_dir.path().join(&host).join("root");
+    let entries = fs::read_dir(&expected_dir).expect("Expected directory not found");
+    let mut found_request = false;
+    for entry in entries {
+        let path = entry.expect("Failed to read directory entry").path();
+        if path.extension().and_then(|s| s.to_str()) == Some("request") {
+            let content = fs::read_to_string(&path).expect("Failed to read request file");
+            assert!(content.starts_with("GET / HTTP/1.1\r\n"));
+            assert!(content.contains(&format!("host: {}\r\n", server.address())));
+            assert!(content.contains("x-test-header: HeaderValue\r\n"));
+            found_request = true;
+        }
+    }
+    assert!(found_request, "Request file not found");
+}
+
+#[tokio::test]
+async fn test_save_request_with_host_override() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("Hello, vhost!");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save the request, sending a Host of our own
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--save-request")
+            .arg("--host")
+            .arg("vhost.test");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that the request file carries only the Host that was sent
+    let host = server.address().ip().to_string();
+    let expected_dir = temp_dir.path().join(&host).join("root");
+    let request_file = fs::read_dir(&expected_dir)
+        .expect("Expected directory not found")
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "request"))
+        .expect("Request file not found");
+    let content = fs::read_to_string(request_file).unwrap();
+    assert!(content.contains("host: vhost.test\r\n"), "{}", content);
+    assert_eq!(content.matches("host: ").count(), 1, "{}", content);
+}
+
+#[tokio::test]
+async fn test_meta_refresh() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Create a page that redirects with a meta refresh
+    let _start = server.mock(|when, then| {
+        when.method(GET).path("/start");
+        then.status(200).header("Content-Type", "text/html").body(
+            r#"<html><head><META HTTP-EQUIV="Refresh" CONTENT="0; URL=/final"></head></html>"#,
+        );
+    });
+
+    let _final = server.mock(|when, then| {
+        when.method(GET).path("/final");
+        then.status(200).body("Final page");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Follow meta refreshes and save all responses
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--meta-refresh");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/start")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that the refresh target was requested
+    assert_eq!(_final.hits(), 1, "Meta refresh target was not requested");
+
+    // Verify that the refresh target was saved
+    let host = server.address().ip().to_string();
+    let expected_dir = temp_dir.path().join(host).join("final");
+    let entries = fs::read_dir(&expected_dir).expect("Expected directory not found");
+    let mut found_body = false;
+    for entry in entries {
+        let path = entry.expect("Failed to read directory entry").path();
+        if path.extension().and_then(|s| s.to_str()) == Some("body") {
+            let content = fs::read_to_string(&path).expect("Failed to read body file");
+            assert_eq!(content, "Final page");
+            found_body = true;
+        }
+    }
+    assert!(found_body, "Response body file not found");
+}
+
+#[tokio::test]
+async fn test_cookies_report() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Create a mock response setting a cookie
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).header(
+            "Set-Cookie",
+            "session=abc123; Path=/; Secure; HttpOnly; SameSite=Strict",
+        );
+    });
+
+    // Use a temporary directory for the report
+    let temp_dir = TempDir::new().unwrap();
+    let report = temp_dir.path().join("cookies.txt");
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Write the cookie report
+    cmd.arg("--cookies-report").arg(&report);
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and capture output
+    cmd.assert().success();
+
+    // Verify that the report notes the cookie flags
+    let content = fs::read_to_string(&report).expect("Failed to read cookie report");
+    assert_eq!(
+        content,
+        format!(
+            "{} session HttpOnly=true Secure=true SameSite=Strict\n",
+            server.url("/")
+        )
+    );
+}
+
+#[test]
+fn test_concurrency_limit() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // Start a server that records how many requests it handles at once
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+    let served = Arc::new(AtomicUsize::new(0));
+
+    {
+        let in_flight = Arc::clone(&in_flight);
+        let max_in_flight = Arc::clone(&max_in_flight);
+        let served = Arc::clone(&served);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let in_flight = Arc::clone(&in_flight);
+                let max_in_flight = Arc::clone(&max_in_flight);
+                let served = Arc::clone(&served);
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 4096];
+                    let _ = stream.read(&mut buf);
+
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(200));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    served.fetch_add(1, Ordering::SeqCst);
+
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    );
+                });
+            }
+        });
+    }
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Limit concurrency to 5 with no delay
+    cmd.arg("-c").arg("5").arg("-d").arg("0");
+
+    // Provide 20 URLs via stdin
+    let input: String = (0..20)
+        .map(|i| format!("http://{}/{}\n", addr, i))
+        .collect();
+    cmd.write_stdin(input);
+
+    // Run the command and capture output
+    cmd.assert().success();
+
+    // Verify that every request was served without exceeding the limit
+    assert_eq!(served.load(Ordering::SeqCst), 20);
+    let max = max_in_flight.load(Ordering::SeqCst);
+    assert!(max <= 5, "Saw {} concurrent requests with -c 5", max);
+}
+
+#[tokio::test]
+async fn test_date_dir() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("Hello, world!");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save under a dated directory with a fixed format
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--date-dir")
+            .arg("--date-format")
+            .arg("scan-%Y");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that the dated directory holds the host tree
+    let entries: Vec<_> = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert!(Regex::new(r"^scan-\d{4}$").unwrap().is_match(&entries[0]));
+
+    let host = server.address().ip().to_string();
+    assert!(temp_dir
+        .path()
+        .join(&entries[0])
+        .join(host)
+        .join("root")
+        .is_dir());
+}
+
+#[tokio::test]
+async fn test_max_content_length() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Create one short and one long response
+    let _short = server.mock(|when, then| {
+        when.method(GET).path("/short");
+        then.status(200).body("tiny");
+    });
+
+    let _long = server.mock(|when, then| {
+        when.method(GET).path("/long");
+        then.status(200).body("a".repeat(100));
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses declaring at most 10 bytes
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--max-content-length")
+            .arg("10");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/short"),
+            server.url("/long")
+        ));
+
+        // Run the command and capture output
+        cmd.assert().success();
+    }
+
+    // Verify that only the short response was saved
+    let host = server.address().ip().to_string();
+    assert!(temp_dir.path().join(&host).join("short").is_dir());
+    assert!(
+        !temp_dir.path().join(&host).join("long").exists(),
+        "Response over the Content-Length limit should not be saved"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_chmod() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("Sensitive");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses readable by the owner only
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--chmod")
+            .arg("600");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that every saved file has the requested mode
+    let host = server.address().ip().to_string();
+    let expected_dir = temp_dir.path().join(host).join("root");
+    let entries = fs::read_dir(&expected_dir).expect("Expected directory not found");
+    let mut files = 0;
+    for entry in entries {
+        let metadata = entry.unwrap().metadata().unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        files += 1;
+    }
+    assert_eq!(files, 2, "Expected a .body and a .headers file");
+}
+
+#[tokio::test]
+async fn test_fsync() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/durable");
+        then.status(200)
+            .header("X-Kept", "yes")
+            .body("Durable body");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses, syncing each file to disk
+        cmd.arg("-o").arg(temp_dir.path()).arg("-S").arg("--fsync");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/durable")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that both files were written in full
+    let expected_dir = temp_dir.path().join("127.0.0.1").join("durable");
+    let mut found_body = false;
+    let mut found_headers = false;
+    for entry in fs::read_dir(&expected_dir).expect("Expected directory not found") {
+        let path = entry.unwrap().path();
+        let content = fs::read_to_string(&path).unwrap();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("body") => {
+                assert_eq!(content, "Durable body");
+                found_body = true;
+            }
+            Some("headers") => {
+                assert!(content.contains("< x-kept: yes"));
+                found_headers = true;
+            }
+            _ => {}
+        }
+    }
+    assert!(found_body && found_headers);
+}
+
+#[tokio::test]
+async fn test_meta_json() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/api");
+        then.status(201)
+            .header("Content-Type", "application/json")
+            .body(r#"{"id":1}"#);
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses with metadata sidecars
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--meta-json");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/api")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that the sidecar exists and records the response
+    let host = server.address().ip().to_string();
+    let expected_dir = temp_dir.path().join(host).join("api");
+    let entries = fs::read_dir(&expected_dir).expect("Expected directory not found");
+    let mut found_meta = false;
+    for entry in entries {
+        let path = entry.expect("Failed to read directory entry").path();
+        if path.extension().and_then(|s| s.to_str()) == Some("json") {
+            let content = fs::read_to_string(&path).expect("Failed to read metadata file");
+            let meta: serde_json::Value = serde_json::from_str(&content).unwrap();
+            assert_eq!(meta["response"]["status"], 201);
+            assert_eq!(meta["response"]["body_size"], 8);
+            assert_eq!(meta["request"]["method"], "GET");
+            assert_eq!(meta["request"]["url"], server.url("/api"));
+            assert_eq!(
+                meta["response"]["headers"]["content-type"][0],
+                "application/json"
+            );
+            found_meta = true;
+        }
+    }
+    assert!(found_meta, "Metadata file not found");
+}
+
+#[tokio::test]
+async fn test_max_header_size_http2() {
+    // Serve HTTP/2 responses with a 4 KiB header
+    let addr = h2_server(
+        || {
+            http::Response::builder()
+                .status(200)
+                .header("x-big", "v".repeat(4096))
+                .body(())
+                .unwrap()
+        },
+        b"big headers",
+    )
+    .await;
+
+    {
+        // Prepare a command allowing only 1 KiB of headers
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+        cmd.arg("--max-header-size").arg("1024");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("https://{}/big\n", addr));
+
+        // Run the command off the runtime the server is on and verify the URL failed
+        let assert = tokio::task::spawn_blocking(move || cmd.assert())
+            .await
+            .unwrap();
+        assert
+            .success()
+            .stdout(predicate::str::contains("200").not())
+            .stderr(predicate::str::contains(format!(
+                "Response headers too large for https://{}/big",
+                addr
+            )))
+            .stderr(predicate::str::contains("Failed requests (headers: 1)"));
+    }
+
+    {
+        // Prepare a command allowing 64 KiB of headers
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+        cmd.arg("--max-header-size").arg("65536");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("https://{}/big\n", addr));
+
+        // Run the command and verify the response came through
+        let assert = tokio::task::spawn_blocking(move || cmd.assert())
+            .await
+            .unwrap();
+        assert.success().stdout(predicate::str::contains(format!(
+            "https://{}/big 200",
+            addr
+        )));
+    }
+}
+
+#[tokio::test]
+async fn test_too_many_http1_headers() {
+    // Start a server answering with more headers than HTTP/1 allows
+    let mut response =
+        String::from("HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n");
+    for i in 0..120 {
+        response.push_str(&format!("X-Header-{}: v\r\n", i));
+    }
+    response.push_str("\r\nok");
+    let (addr, _) = scripted_server(vec![Box::leak(response.into_boxed_str())]);
+
+    // Start a mock server answering normally
+    let server = MockServer::start_async().await;
+    server.mock(|when, then| {
+        when.method(GET).path("/fine");
+        then.status(200).body("fine");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Provide both URLs via stdin
+    cmd.write_stdin(format!("http://{}/many\n{}\n", addr, server.url("/fine")));
+
+    // Run the command and verify only the first URL failed, as oversized headers
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "Response headers too large for http://{}/many",
+            addr
+        )))
+        .stderr(predicate::str::contains("Failed requests (headers: 1)"))
+        .stdout(predicate::str::contains(format!(
+            "{} 200",
+            server.url("/fine")
+        )));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_chmod_shared_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("Sensitive");
+    });
+
+    // Save one run per host file with a report, and one flat with a manifest
+    let per_host = TempDir::new().unwrap();
+    let flat = TempDir::new().unwrap();
+    for (dir, layout) in [(&per_host, "--per-host-file"), (&flat, "--flat")] {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses readable by the owner only, synced to disk
+        cmd.arg("-o")
+            .arg(dir.path())
+            .arg("-S")
+            .arg("--chmod")
+            .arg("600")
+            .arg("--fsync")
+            .arg("--report")
+            .arg(layout);
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that the shared files have the requested mode too
+    let host = server.address().ip().to_string();
+    for path in [
+        per_host.path().join(format!("{}.txt", host)),
+        per_host.path().join("index.html"),
+        flat.path().join("manifest.tsv"),
+    ] {
+        let metadata = fs::metadata(&path).expect("Expected file not found");
+        assert_eq!(
+            metadata.permissions().mode() & 0o7777,
+            0o600,
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_dns_failure_is_categorised() {
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Provide an unresolvable URL via stdin
+    cmd.write_stdin("http://fff-test.invalid/\n");
+
+    // Run the command and verify the error is reported as a DNS failure
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "DNS resolution failed for http://fff-test.invalid/",
+        ))
+        .stderr(predicate::str::contains("Failed requests (dns: 1)"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_exec_on_saved_body() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("secret=hunter2");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Print each saved body through a shell command
+    cmd.arg("-o")
+        .arg(temp_dir.path())
+        .arg("-S")
+        .arg("--exec")
+        .arg("echo {status}; cat {file}");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and verify the command output is forwarded
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200\nsecret=hunter2"));
+}
+
+#[tokio::test]
+async fn test_proxy_file_rotation() {
+    // Start two mock servers to act as proxies
+    let proxy_a = MockServer::start_async().await;
+    let proxy_b = MockServer::start_async().await;
+
+    let mock_a = proxy_a.mock(|when, then| {
+        when.any_request();
+        then.status(200);
+    });
+
+    let mock_b = proxy_b.mock(|when, then| {
+        when.any_request();
+        then.status(200);
+    });
+
+    // List both proxies in a file
+    let temp_dir = TempDir::new().unwrap();
+    let proxy_file = temp_dir.path().join("proxies.txt");
+    fs::write(
+        &proxy_file,
+        format!(
+            "http://{}\n\nhttp://{}\n",
+            proxy_a.address(),
+            proxy_b.address()
+        ),
+    )
+    .unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Rotate through the proxies
+    cmd.arg("--proxy-file").arg(&proxy_file);
+
+    // Provide the URLs via stdin
+    cmd.write_stdin(
+        "http://example.com/1\nhttp://example.com/2\nhttp://example.com/3\nhttp://example.com/4\n",
+    );
+
+    // Run the command and capture output
+    cmd.assert().success();
+
+    // Verify that both proxies received traffic
+    assert_eq!(mock_a.hits(), 2, "First proxy was not used");
+    assert_eq!(mock_b.hits(), 2, "Second proxy was not used");
+}
+
+#[tokio::test]
+async fn test_proxy_map() {
+    // Start two mock servers to act as proxies, each expecting one host
+    let proxy_a = MockServer::start_async().await;
+    let proxy_b = MockServer::start_async().await;
+
+    let mock_a = proxy_a.mock(|when, then| {
+        when.any_request().header("host", "a.example.com");
+        then.status(200);
+    });
+    let mock_b = proxy_b.mock(|when, then| {
+        when.any_request().header("host", "b.example.com");
+        then.status(200);
+    });
+
+    // Map each host to its proxy, in both accepted forms
+    let temp_dir = TempDir::new().unwrap();
+    let proxy_map = temp_dir.path().join("proxies.txt");
+    fs::write(
+        &proxy_map,
+        format!(
+            "# host proxy\na.example.com http://{}\nB.example.com -> http://{}\n",
+            proxy_a.address(),
+            proxy_b.address()
+        ),
+    )
+    .unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Route by host
+    cmd.arg("--proxy-map").arg(&proxy_map);
+
+    // Provide the URLs via stdin
+    cmd.write_stdin(
+        "http://a.example.com/1\nhttp://b.example.com/1\nhttp://a.example.com/2\nhttp://b.example.com/2\n",
+    );
+
+    // Run the command and capture output
+    cmd.assert().success();
+
+    // Verify that each proxy only received its own host's requests
+    assert_eq!(mock_a.hits(), 2, "First proxy got the wrong requests");
+    assert_eq!(mock_b.hits(), 2, "Second proxy got the wrong requests");
+}
+
+#[tokio::test]
+async fn test_resolve_and_host_override() {
+    // Start a mock server that only answers one virtual host
+    let server = MockServer::start_async().await;
+
+    let vhost = server.mock(|when, then| {
+        when.method(GET).path("/").header("host", "admin.fff.test");
+        then.status(200).body("admin panel");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Pin an unresolvable name to the mock server and ask for another vhost
+    cmd.arg("--resolve")
+        .arg("www.fff.test:127.0.0.1")
+        .arg("--host")
+        .arg("admin.fff.test")
+        .arg("-H")
+        .arg("Host: ignored.fff.test");
+
+    // Provide the URL via stdin
+    let url = format!("http://www.fff.test:{}/", server.port());
+    cmd.write_stdin(format!("{}\n", url));
+
+    // Run the command and verify the pinned address served it
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(format!("{} 200", url)));
+
+    // Verify that the mock observed the overridden Host
+    vhost.assert_hits(1);
+}
+
+#[tokio::test]
+async fn test_adaptive_delay_on_429() {
+    // Start a server that rate limits the first two requests
+    let too_many =
+        "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let ok = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+    let (addr, requests) = scripted_server(vec![too_many, too_many, ok]);
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Back off on 429s
+    cmd.arg("--adaptive-delay").arg("-d").arg("0");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("http://{}/\n", addr));
+
+    // Record the time before running
+    let start_time = std::time::Instant::now();
+
+    // Run the command and verify the request eventually succeeds
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200"))
+        .stderr(predicate::str::contains("Rate limited by 127.0.0.1"));
+
+    // Verify that the retries waited 500ms and then 1000ms
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    assert!(
+        start_time.elapsed() >= Duration::from_millis(1500),
+        "Requests were not slowed down"
+    );
+}
+
+#[tokio::test]
+async fn test_impersonate_chrome() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Only match requests carrying Chrome's headers
+    let _mock = server.mock(|when, then| {
+        when.method(GET)
+            .path("/")
+            .matches(|req| {
+                req.headers
+                    .iter()
+                    .flatten()
+                    .any(|(k, v)| k.eq_ignore_ascii_case("user-agent") && v.contains("Chrome/"))
+            })
+            .header("accept-language", "en-US,en;q=0.9")
+            .header("sec-ch-ua-mobile", "?0");
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Impersonate Chrome
+    cmd.arg("--impersonate").arg("chrome");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and capture output
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200"));
+
+    // Verify that the mock saw the browser headers
+    assert_eq!(_mock.hits(), 1, "Browser headers were not sent");
+}
+
+#[tokio::test]
+async fn test_diff_against_prior_capture() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let _same = server.mock(|when, then| {
+        when.method(GET).path("/same");
+        then.status(200).body("unchanged\n");
+    });
+
+    let mut changing = server.mock(|when, then| {
+        when.method(GET).path("/changing");
+        then.status(200).body("version 1\n");
+    });
+
+    // Capture a baseline
+    let prior_dir = TempDir::new().unwrap();
+    let input = format!("{}\n{}\n", server.url("/same"), server.url("/changing"));
+    Command::cargo_bin("fff")
+        .unwrap()
+        .arg("-o")
+        .arg(prior_dir.path())
+        .arg("-S")
+        .write_stdin(input.clone())
+        .assert()
+        .success();
+
+    // Change one of the responses
+    changing.delete();
+    let _changed = server.mock(|when, then| {
+        when.method(GET).path("/changing");
+        then.status(200).body("version 2\n");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Diff against the baseline
+    cmd.arg("-o")
+        .arg(temp_dir.path())
+        .arg("-S")
+        .arg("--diff")
+        .arg(prior_dir.path())
+        .arg("--show-diff");
+
+    // Provide the URLs via stdin
+    cmd.write_stdin(input);
+
+    // Run the command and verify only the changed response is reported
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("/changing"))
+        .stdout(predicate::str::contains("[changed]"))
+        .stdout(predicate::str::contains("-version 1\n+version 2\n"))
+        .stdout(predicate::str::contains("/same").not());
+
+    // Verify that only the changed response was saved
+    let host = server.address().ip().to_string();
+    assert!(temp_dir.path().join(&host).join("changing").is_dir());
+    assert!(!temp_dir.path().join(&host).join("same").exists());
+}
+
+#[tokio::test]
+async fn test_no_default_headers() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Only match requests without the client's optional headers
+    let _mock = server.mock(|when, then| {
+        when.method(GET).path("/").matches(|req| {
+            req.headers.iter().flatten().all(|(k, _)| {
+                !k.eq_ignore_ascii_case("accept-encoding") && !k.eq_ignore_ascii_case("user-agent")
+            })
+        });
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Send a minimal request
+    cmd.arg("--no-default-headers");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and capture output
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200"));
+
+    // Verify that the mock saw no default headers
+    assert_eq!(_mock.hits(), 1, "Default headers were sent");
+}
+
+#[tokio::test]
+async fn test_record_redirects() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Create a 302 -> 302 -> 200 chain
+    let _first = server.mock(|when, then| {
+        when.method(GET).path("/a");
+        then.status(302).header("Location", "/b");
+    });
+
+    let _second = server.mock(|when, then| {
+        when.method(GET).path("/b");
+        then.status(302).header("Location", "/c");
+    });
+
+    let _last = server.mock(|when, then| {
+        when.method(GET).path("/c");
+        then.status(200).body("Done");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses with the redirect chain
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--record-redirects");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/a")));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved (200)"));
+    }
+
+    // Verify that every hop is recorded in the headers file
+    let host = server.address().ip().to_string();
+    let expected_dir = temp_dir.path().join(host).join("c");
+    let entries = fs::read_dir(&expected_dir).expect("Expected directory not found");
+    let mut found_headers = false;
+    for entry in entries {
+        let path = entry.expect("Failed to read directory entry").path();
+        if path.extension().and_then(|s| s.to_str()) == Some("headers") {
+            let content = fs::read_to_string(&path).expect("Failed to read headers file");
+            assert!(content.contains(&format!(
+                "* Redirect: 302 {}\n* Redirect: 302 {}\n\n< HTTP/1.1 200 OK\n",
+                server.url("/b"),
+                server.url("/c")
+            )));
+            found_headers = true;
+        }
+    }
+    assert!(found_headers, "Headers file not found");
+}
+
+#[tokio::test]
+async fn test_record_redirects_strips_credentials() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Redirect a POST to the same server under another host name
+    let _first = server.mock(|when, then| {
+        when.method(POST).path("/login");
+        then.status(302).header(
+            "Location",
+            format!("http://localhost:{}/next", server.port()),
+        );
+    });
+
+    let next = server.mock(|when, then| {
+        when.method(GET).path("/next").matches(|req| {
+            req.headers.iter().flatten().all(|(k, _)| {
+                !k.eq_ignore_ascii_case("authorization") && !k.eq_ignore_ascii_case("content-type")
+            })
+        });
+        then.status(200).body("Done");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Send credentials and a body, following redirects by hand
+    cmd.arg("-m")
+        .arg("POST")
+        .arg("-b")
+        .arg("user=fff")
+        .arg("-H")
+        .arg("Authorization: Bearer secret")
+        .arg("-H")
+        .arg("Content-Type: application/x-www-form-urlencoded")
+        .arg("--record-redirects");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/login")));
+
+    // Run the command and verify the redirect was followed
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200"));
+
+    // Verify that the second hop got neither the credentials nor the body headers
+    next.assert_hits(1);
+}
+
+#[tokio::test]
+async fn test_batches_with_pause() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET);
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Send batches of two with a 300ms pause in between
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--batch-size")
+        .arg("2")
+        .arg("--batch-pause")
+        .arg("300");
+
+    // Provide four URLs via stdin
+    let input: String = (1..=4)
+        .map(|i| format!("{}\n", server.url(format!("/{}", i))))
+        .collect();
+    cmd.write_stdin(input);
+
+    // Record the time before running
+    let start_time = std::time::Instant::now();
+
+    // Run the command and capture output
+    cmd.assert().success();
+
+    // Verify that exactly one pause separated the two batches
+    let elapsed = start_time.elapsed();
+    assert_eq!(mock.hits(), 4);
+    assert!(
+        elapsed >= Duration::from_millis(300),
+        "Batches were not paused"
+    );
+    assert!(
+        elapsed < Duration::from_millis(600),
+        "Paused more than once: {:?}",
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn test_host_dir_includes_port() {
+    // Start two mock servers on the same host
+    let server_a = MockServer::start_async().await;
+    let server_b = MockServer::start_async().await;
+
+    for server in [&server_a, &server_b] {
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).body("Hello");
+        });
+    }
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses under host_port directories
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--host-dir")
+            .arg("host-port");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{}\n{}\n", server_a.url("/"), server_b.url("/")));
+
+        // Run the command and capture output
+        cmd.assert().success();
+    }
+
+    // Verify that the two ports didn't collide
+    for server in [&server_a, &server_b] {
+        let dir = format!("127.0.0.1_{}", server.address().port());
+        assert!(
+            temp_dir.path().join(dir).join("root").is_dir(),
+            "Missing directory for port {}",
+            server.address().port()
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_host_dir_ipv6() {
+    // Start a server on the IPv6 loopback, if the host has one
+    let listener = match std::net::TcpListener::bind("[::1]:0") {
+        Ok(l) => l,
+        Err(_) => return,
+    };
+    let ok = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nHello";
+    let (addr, _) = scripted_server_on(listener, vec![ok]);
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses under host_port directories
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--host-dir")
+            .arg("host-port");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("http://[::1]:{}/\n", addr.port()));
+
+        // Run the command and capture output
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved"));
+    }
+
+    // Verify that the IPv6 host has no colons in its directory name
+    let dir = format!("[--1]_{}", addr.port());
+    assert!(temp_dir.path().join(dir).join("root").is_dir());
+}
+
+#[tokio::test]
+async fn test_preflight_failure_aborts() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Preflight against a closed port
+    cmd.arg("--preflight").arg("http://127.0.0.1:1/");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and verify it aborts
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Preflight check failed: http://127.0.0.1:1/",
+    ));
+
+    // Verify that stdin was never processed
+    assert_eq!(
+        mock.hits(),
+        0,
+        "URLs were requested after a failed preflight"
+    );
+}
+
+#[tokio::test]
+async fn test_body_from_stdin() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Expect a POST carrying the body piped on stdin
+    let mock = server.mock(|when, then| {
+        when.method(POST).path("/").body("{\"id\": 1}\n");
+        then.status(200);
+    });
+
+    // Write the URL list to a file
+    let temp_dir = TempDir::new().unwrap();
+    let urls = temp_dir.path().join("urls.txt");
+    fs::write(&urls, format!("{}\n", server.url("/"))).unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Read the body from stdin and the URLs from the file
+    cmd.arg("--body-from-stdin").arg("--input").arg(&urls);
+
+    // Provide the body via stdin
+    cmd.write_stdin("{\"id\": 1}\n");
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    // Verify that the body was POSTed
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_binary_body_from_stdin() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Expect a POST carrying the exact bytes piped on stdin
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/")
+            .matches(|req| req.body.as_deref() == Some(&[0xff, 0xfe, 0x00, 0x80][..]));
+        then.status(200);
+    });
+
+    // Write the URL list to a file
+    let temp_dir = TempDir::new().unwrap();
+    let urls = temp_dir.path().join("urls.txt");
+    fs::write(&urls, format!("{}\n", server.url("/"))).unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Read the body from stdin and the URLs from the file
+    cmd.arg("--body-from-stdin").arg("--input").arg(&urls);
+
+    // Provide a body that isn't UTF-8 via stdin
+    cmd.write_stdin(vec![0xff, 0xfe, 0x00, 0x80]);
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    // Verify that the body was POSTed unchanged
+    mock.assert();
+}
+
+#[test]
+fn test_rate_with_jitter() {
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    // Serve empty responses and record when each request arrives
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let arrivals = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = Arc::clone(&arrivals);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            recorded.lock().unwrap().push(Instant::now());
+            let mut buf = [0u8; 8192];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // 20 requests per second, each interval varied by up to 50%
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--rate")
+        .arg("20")
+        .arg("--jitter")
+        .arg("50");
+
+    // Provide 21 URLs via stdin
+    let urls: String = (0..21)
+        .map(|i| format!("http://{}/{}\n", addr, i))
+        .collect();
+    cmd.write_stdin(urls);
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    let mut arrivals = arrivals.lock().unwrap().clone();
+    arrivals.sort();
+    assert_eq!(arrivals.len(), 21);
+
+    // Verify that the average interval stays near 50ms
+    let total = arrivals[20] - arrivals[0];
+    assert!(
+        total >= Duration::from_millis(700) && total <= Duration::from_millis(1400),
+        "20 intervals took {:?}",
+        total
+    );
+
+    // Verify that individual intervals vary
+    let gaps: Vec<Duration> = arrivals.windows(2).map(|w| w[1] - w[0]).collect();
+    let shortest = gaps.iter().min().unwrap();
+    let longest = gaps.iter().max().unwrap();
+    assert!(
+        *longest - *shortest >= Duration::from_millis(15),
+        "intervals did not vary: {:?}",
+        gaps
+    );
+}
+
+#[test]
+fn test_invalid_rate() {
+    for rate in ["0", "-5", "inf", "NaN", "1e-20"] {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+        cmd.arg(format!("--rate={}", rate));
+
+        // Run the command and verify the rate is rejected as a usage error
+        cmd.assert()
+            .code(2)
+            .stderr(predicate::str::contains("--rate"));
+    }
+}
+
+#[tokio::test]
+async fn test_expect_status() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/up");
+        then.status(200);
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/down");
+        then.status(500);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Expect every URL to answer 200
+    cmd.arg("--expect").arg("200");
+
+    // Provide the URLs via stdin
+    cmd.write_stdin(format!("{}\n{}\n", server.url("/up"), server.url("/down")));
+
+    // Run the command and verify that only the 500 is reported as a failure
+    cmd.assert()
+        .failure()
+        .code(1)
+        .stderr(predicate::str::contains(format!(
+            "Unexpected status for {}: 500 (expected 200)",
+            server.url("/down")
+        )))
+        .stderr(predicate::str::contains(server.url("/up")).not());
+}
+
+#[tokio::test]
+async fn test_max_time() {
+    // Start a mock server that takes far longer than the time limit
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/slow");
+        then.status(200).delay(Duration::from_secs(30));
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Cap the whole run at one second
+    cmd.arg("-d").arg("0").arg("--max-time").arg("1");
+
+    // Provide the URLs via stdin
+    cmd.write_stdin(format!(
+        "{}\n{}\n",
+        server.url("/slow"),
+        server.url("/slow")
+    ));
+
+    // Run the command and verify that it stops near the deadline
+    let started = std::time::Instant::now();
+    cmd.assert().success().stderr(predicate::str::contains(
+        "Time limit of 1s reached: stopped after 2 requests, cancelled 2 in flight",
+    ));
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(5),
+        "run took {:?} despite --max-time 1",
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn test_same_host_redirects() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Redirect within the host, then to another host name for the same server
+    server.mock(|when, then| {
+        when.method(GET).path("/start");
+        then.status(302).header("Location", "/same");
+    });
+    let same = server.mock(|when, then| {
+        when.method(GET).path("/same");
+        then.status(302).header(
+            "Location",
+            format!("http://localhost:{}/offhost", server.port()),
+        );
+    });
+    let offhost = server.mock(|when, then| {
+        when.method(GET).path("/offhost");
+        then.status(200);
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses and only follow same-host redirects
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--same-host-redirects");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/start")));
+
+        // Run the command and check that the off-host redirect is what's reported
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("302"));
+    }
+
+    // Verify that the same-host hop was followed but the off-host one wasn't
+    same.assert();
+    assert_eq!(offhost.hits(), 0, "followed a redirect to another host");
+
+    // Verify that the off-host redirect response was saved under the last hop
+    let host_dir = temp_dir.path().join("127.0.0.1");
+    let saved = fs::read_dir(host_dir.join("same"))
+        .unwrap()
+        .filter_map(Result::ok)
+        .map(|e| fs::read_to_string(e.path()).unwrap())
+        .find(|c| c.contains("302"))
+        .expect("off-host redirect was not saved");
+    assert!(saved.contains("/offhost"));
+}
+
+#[test]
+fn test_shuffle_headers() {
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+
+    // Serve empty responses and record the X- headers of each request in order
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let orders = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = Arc::clone(&orders);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 8192];
+            let n = stream.read(&mut buf).unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+            let order: Vec<String> = request
+                .lines()
+                .filter(|l| l.starts_with("x-"))
+                .map(str::to_string)
+                .collect();
+            recorded.lock().unwrap().push(order);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Shuffle four headers with a fixed seed
+    cmd.arg("-d").arg("0");
+    for h in ["X-A: 1", "X-B: 2", "X-C: 3", "X-D: 4"] {
+        cmd.arg("-H").arg(h);
+    }
+    cmd.arg("--shuffle-headers").arg("--shuffle-seed").arg("7");
+
+    // Provide the URLs via stdin
+    let urls: String = (0..10)
+        .map(|i| format!("http://{}/{}\n", addr, i))
+        .collect();
+    cmd.write_stdin(urls);
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    let orders = orders.lock().unwrap();
+    assert_eq!(orders.len(), 10);
+
+    // Verify that every request carried all of the headers
+    for order in orders.iter() {
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec!["x-a: 1", "x-b: 2", "x-c: 3", "x-d: 4"]);
+    }
+
+    // Verify that the order varied between requests
+    assert!(
+        orders.iter().any(|o| o != &orders[0]),
+        "every request used the same header order: {:?}",
+        orders[0]
+    );
+}
+
+#[tokio::test]
+async fn test_flat_output() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/a/b");
+        then.status(200).body("flat");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses without the host/path tree
+        cmd.arg("-o").arg(temp_dir.path()).arg("-S").arg("--flat");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/a/b")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that everything sits directly in the output root
+    let mut names: Vec<String> = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|e| {
+            let entry = e.unwrap();
+            assert!(entry.file_type().unwrap().is_file());
+            entry.file_name().into_string().unwrap()
+        })
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 3, "unexpected files: {:?}", names);
+    assert_eq!(names[2], "manifest.tsv");
+
+    // Verify that the manifest maps the hash back to the URL
+    let hash = names[0].trim_end_matches(".body");
+    let manifest = fs::read_to_string(temp_dir.path().join("manifest.tsv")).unwrap();
+    assert_eq!(manifest, format!("{}\t200\t{}\n", hash, server.url("/a/b")));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join(&names[0])).unwrap(),
+        "flat"
+    );
+}
+
+#[test]
+fn test_per_host_concurrency() {
+    use std::io::{Read, Write};
+
+    // Serve slow responses and track the most connections open at once
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let current = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+
+    let (open, max) = (Arc::clone(&current), Arc::clone(&peak));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let (open, max) = (Arc::clone(&open), Arc::clone(&max));
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 8192];
+                let _ = stream.read(&mut buf);
+                let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(200));
+                open.fetch_sub(1, Ordering::SeqCst);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            });
+        }
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Allow plenty of global concurrency but only two requests per host
+    cmd.arg("-d")
+        .arg("0")
+        .arg("-c")
+        .arg("10")
+        .arg("--per-host-concurrency")
+        .arg("2");
+
+    // Provide the URLs via stdin
+    let urls: String = (0..6).map(|i| format!("http://{}/{}\n", addr, i)).collect();
+    cmd.write_stdin(urls);
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    // Verify that the host never saw more than two requests at once
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_per_host_concurrency_leaves_other_hosts() {
+    use std::io::{Read, Write};
+    use std::time::Instant;
+
+    // Serve one host slowly and another quickly, recording when the quick one is asked
+    let slow = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let slow_addr = slow.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in slow.incoming() {
+            let mut stream = stream.unwrap();
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 8192];
+                let _ = stream.read(&mut buf);
+                std::thread::sleep(Duration::from_millis(1000));
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            });
+        }
+    });
+    let fast = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let fast_addr = fast.local_addr().unwrap();
+    let asked = Arc::new(std::sync::Mutex::new(None));
+    let recorder = Arc::clone(&asked);
+    std::thread::spawn(move || {
+        for stream in fast.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 8192];
+            let _ = stream.read(&mut buf);
+            recorder.lock().unwrap().get_or_insert(Instant::now());
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Two requests at once overall, one per host
+    cmd.arg("-d")
+        .arg("0")
+        .arg("-c")
+        .arg("2")
+        .arg("--per-host-concurrency")
+        .arg("1");
+
+    // Provide two slow URLs, then a quick one, via stdin
+    cmd.write_stdin(format!(
+        "http://localhost:{0}/a\nhttp://localhost:{0}/b\nhttp://{1}/\n",
+        slow_addr.port(),
+        fast_addr
+    ));
+
+    // Run the command and check it succeeded
+    let start = Instant::now();
+    cmd.assert().success();
+
+    // Verify that the quick host wasn't kept waiting behind the slow one
+    let asked = asked.lock().unwrap().expect("quick host never asked");
+    assert!(
+        asked - start < Duration::from_millis(800),
+        "quick host asked after {:?}",
+        asked - start
+    );
+}
+
+#[tokio::test]
+async fn test_headers_only_when_empty() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/empty");
+        then.status(204);
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses, skipping the body file when it's empty
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--headers-only-when-empty");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/empty")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that only the .headers file was written
+    let saved: Vec<String> = fs::read_dir(temp_dir.path().join("127.0.0.1").join("empty"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(saved.len(), 1, "unexpected files: {:?}", saved);
+    assert!(saved[0].ends_with(".headers"));
+}
+
+#[test]
+fn test_drop_body() {
+    // Serve a response promising a large body but sending only a little before closing
+    let (addr, _) = scripted_server(vec![
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 10000000\r\n\r\npartial",
+    ]);
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses without reading their bodies
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--drop-body");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("http://{}/big\n", addr));
+
+        // Run the command and check the truncated body went unnoticed
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("Failed to read body").not());
+    }
+
+    // Verify that only the .headers file was written
+    let dir = temp_dir.path().join("127.0.0.1").join("big");
+    let saved: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(saved.len(), 1, "unexpected files: {:?}", saved);
+    assert!(saved[0].ends_with(".headers"));
+
+    // Verify that the headers were kept
+    let headers = fs::read_to_string(dir.join(&saved[0])).unwrap();
+    assert!(headers.contains("< content-length: 10000000"));
+}
+
+#[test]
+fn test_trace_header() {
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+
+    // Serve empty responses and record each request's trace header
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let recorded = Arc::clone(&seen);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 8192];
+            let n = stream.read(&mut buf).unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+            if let Some(id) = request
+                .lines()
+                .find_map(|l| l.strip_prefix("x-request-id: "))
+            {
+                recorded.lock().unwrap().push(id.to_string());
+            }
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Tag each request with a trace header
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--trace-header")
+        .arg("X-Request-Id");
+
+    // Provide the URLs via stdin
+    let urls: Vec<String> = (0..3).map(|i| format!("http://{}/{}", addr, i)).collect();
+    cmd.write_stdin(urls.join("\n") + "\n");
+
+    // Run the command and check it succeeded
+    let output = cmd.assert().success().get_output().clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    // Verify that every request carried a distinct UUID
+    let mut ids = seen.lock().unwrap().clone();
+    assert_eq!(ids.len(), 3);
+    let uuid = Regex::new(r"^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$")
+        .unwrap();
+    assert!(ids.iter().all(|id| uuid.is_match(id)), "{:?}", ids);
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 3, "trace ids were reused");
+
+    // Verify that each id was logged against its URL
+    for id in &ids {
+        assert!(
+            urls.iter()
+                .any(|url| stderr.contains(&format!("{} -> {}", url, id))),
+            "{} was not logged",
+            id
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_skip_lines() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mocks: Vec<_> = (0..5)
+        .map(|i| {
+            server.mock(|when, then| {
+                when.method(GET).path(format!("/{}", i));
+                then.status(200);
+            })
+        })
+        .collect();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Skip the first two lines of input
+    cmd.arg("-d").arg("0").arg("--skip-lines").arg("2");
+
+    // Provide the URLs via stdin
+    let urls: String = (0..5)
+        .map(|i| format!("{}\n", server.url(format!("/{}", i))))
+        .collect();
+    cmd.write_stdin(urls.clone());
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    // Verify that only the last three URLs were requested
+    let hits: Vec<usize> = mocks.iter().map(|m| m.hits()).collect();
+    assert_eq!(hits, vec![0, 0, 1, 1, 1]);
+
+    // Run again over a window of two lines starting at the second
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--skip-lines")
+        .arg("1")
+        .arg("--limit")
+        .arg("2");
+    cmd.write_stdin(urls);
+    cmd.assert().success();
+
+    // Verify that only the window was requested
+    let hits: Vec<usize> = mocks.iter().map(|m| m.hits()).collect();
+    assert_eq!(hits, vec![0, 1, 2, 1, 1]);
+}
+
+#[tokio::test]
+async fn test_shards_cover_input() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mocks: Vec<_> = (0..5)
+        .map(|i| {
+            server.mock(|when, then| {
+                when.method(GET).path(format!("/{}", i));
+                then.status(200);
+            })
+        })
+        .collect();
+
+    let urls: String = (0..5)
+        .map(|i| format!("{}\n", server.url(format!("/{}", i))))
+        .collect();
+
+    // Run one instance per shard over the same list
+    for shard in ["1/2", "2/2"] {
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+        cmd.arg("-d").arg("0").arg("--shard").arg(shard);
+        cmd.write_stdin(urls.clone());
+        cmd.assert().success();
+    }
+
+    // Verify that every URL was requested exactly once
+    let hits: Vec<usize> = mocks.iter().map(|m| m.hits()).collect();
+    assert_eq!(hits, vec![1, 1, 1, 1, 1]);
+}
+
+#[tokio::test]
+async fn test_body_hash_in_headers() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("hash me");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses, recording the body hash
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--body-hash-in-headers");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Find the saved headers file
+    let headers_file = fs::read_dir(temp_dir.path().join("127.0.0.1").join("root"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "headers"))
+        .unwrap();
+    let headers = fs::read_to_string(headers_file).unwrap();
+
+    // Verify that the recorded hash matches the body
+    let expected = format!(
+        "< X-FFF-Body-XXH3: {:016x}\n",
+        xxhash_rust::xxh3::xxh3_64(b"hash me")
+    );
+    assert!(headers.contains(&expected), "headers were: {}", headers);
+}
+
+#[tokio::test]
+async fn test_body_hash_with_save_bytes() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("hash only this prefix");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save the first 9 bytes of each body, recording the body hash
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--save-bytes")
+            .arg("9")
+            .arg("--body-hash-in-headers");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Find the saved files
+    let files: Vec<PathBuf> = fs::read_dir(temp_dir.path().join("127.0.0.1").join("root"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    let headers_file = files
+        .iter()
+        .find(|p| p.extension().is_some_and(|e| e == "headers"))
+        .unwrap();
+    let body_file = files
+        .iter()
+        .find(|p| p.extension().is_some_and(|e| e != "headers"))
+        .unwrap();
+    let headers = fs::read_to_string(headers_file).unwrap();
+    let body = fs::read(body_file).unwrap();
+
+    // Verify that the recorded hash matches the saved prefix
+    assert_eq!(body, b"hash only");
+    let expected = format!(
+        "< X-FFF-Body-XXH3: {:016x}\n",
+        xxhash_rust::xxh3::xxh3_64(&body)
+    );
+    assert!(headers.contains(&expected), "headers were: {}", headers);
+}
+
+#[tokio::test]
+async fn test_long_path_segment() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let segment = "a".repeat(300);
+    server.mock(|when, then| {
+        when.method(GET).path(format!("/{}/x", segment));
+        then.status(200).body("deep");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses
+        cmd.arg("-o").arg(temp_dir.path()).arg("-S");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url(format!("/{}/x", segment))));
+
+        // Run the command and check the save succeeded
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved (200)"));
+    }
+
+    // Verify that the segment was cut to fit and still holds the response
+    let dir = fs::read_dir(temp_dir.path().join("127.0.0.1"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    let name = dir.file_name().into_string().unwrap();
+    assert_eq!(name.len(), 255);
+    assert!(name.starts_with("aaaa"));
+    assert!(dir.path().join("x").is_dir());
+}
+
+#[tokio::test]
+async fn test_match_file() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/hit");
+        then.status(200)
+            .body("an error occurred: stack trace follows");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/miss");
+        then.status(200).body("nothing to see here");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    // Write several candidate strings to a file
+    let patterns = temp_dir.path().join("patterns.txt");
+    fs::write(&patterns, "SQL syntax\nstack trace\nWarning: include(\n").unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save responses matching any string in the file
+        cmd.arg("-o")
+            .arg(temp_dir.path().join("out"))
+            .arg("--match-file")
+            .arg(&patterns);
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{}\n{}\n", server.url("/hit"), server.url("/miss")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that only the matching response was saved
+    let host_dir = temp_dir.path().join("out").join("127.0.0.1");
+    assert!(host_dir.join("hit").is_dir());
+    assert!(!host_dir.join("miss").exists());
+}
+
+#[tokio::test]
+async fn test_baseline_drops_soft_404() {
+    // Start a mock server that answers unknown paths with a generic page
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/real");
+        then.status(200).body("real content");
+    });
+    server.mock(|when, then| {
+        when.method(GET);
+        then.status(200).body("Sorry, page not found");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses except those matching the baseline
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--baseline")
+            .arg(server.url("/does-not-exist"));
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/real"),
+            server.url("/fake")
+        ));
+
+        // Run the command and verify that only the real page is reported
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(server.url("/real")))
+            .stdout(predicate::str::contains(server.url("/fake")).not());
+    }
+
+    // Verify that the soft-404 wasn't saved
+    let host_dir = temp_dir.path().join("127.0.0.1");
+    assert!(host_dir.join("real").is_dir());
+    assert!(!host_dir.join("fake").exists());
+}
+
+#[tokio::test]
+async fn test_baseline_similarity() {
+    // Start a mock server whose error page echoes the requested path
+    let server = MockServer::start_async().await;
+
+    let page = |path: &str| {
+        format!(
+            "<html><body><h1>Not Found</h1><p>The page {} could not be found on this server. \
+             Please check the address and try again.</p></body></html>",
+            path
+        )
+    };
+    for path in ["/nope-baseline", "/admin"] {
+        let body = page(path);
+        server.mock(move |when, then| {
+            when.method(GET).path(path);
+            then.status(200).body(body);
+        });
+    }
+    server.mock(|when, then| {
+        when.method(GET).path("/login");
+        then.status(200)
+            .body("<html><body><form action=\"/session\">Sign in</form></body></html>");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses except near-copies of the baseline
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--baseline")
+            .arg(server.url("/nope-baseline"))
+            .arg("--similarity")
+            .arg("0.9");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/admin"),
+            server.url("/login")
+        ));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that the templated error page was dropped and the real page kept
+    let host_dir = temp_dir.path().join("127.0.0.1");
+    assert!(!host_dir.join("admin").exists());
+    assert!(host_dir.join("login").is_dir());
+}
+
+#[tokio::test]
+async fn test_show_header() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .header("Server", "nginx")
+            .header("X-Powered-By", "PHP/8.1");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Show two response headers, whatever case their names are given in
+    cmd.arg("--show-header")
+        .arg("Server")
+        .arg("--show-header")
+        .arg("x-powered-by");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and verify that the values are printed under lower-case names
+    cmd.assert().success().stdout(predicate::str::contains(
+        "[server: nginx] [x-powered-by: PHP/8.1]",
+    ));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_link_dupes() {
+    // Start a mock server that returns the same body on two paths
+    let server = MockServer::start_async().await;
+
+    for path in ["/first", "/second"] {
+        server.mock(move |when, then| {
+            when.method(GET).path(path);
+            then.status(200).body("same body");
+        });
+    }
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses, linking duplicate bodies; one at a time so the
+        // first URL is saved first
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("-c")
+            .arg("1")
+            .arg("--dedupe-body")
+            .arg("--link-dupes");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/first"),
+            server.url("/second")
+        ));
+
+        // Run the command and check that the duplicate was linked
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Linked (200)"));
+    }
+
+    let body_in = |dir: &str| {
+        fs::read_dir(temp_dir.path().join("127.0.0.1").join(dir))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "body"))
+            .unwrap()
+    };
+    let first = body_in("first");
+    let second = body_in("second");
+
+    // Verify that the second body is a relative symlink to the first
+    assert!(!fs::symlink_metadata(&first).unwrap().is_symlink());
+    assert!(fs::symlink_metadata(&second).unwrap().is_symlink());
+    let target = fs::read_link(&second).unwrap();
+    assert!(target.is_relative());
+    assert_eq!(
+        fs::canonicalize(&second).unwrap(),
+        fs::canonicalize(&first).unwrap()
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_interface_binding() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Bind to an interface that doesn't exist; this fails at connect time
+    // whether or not we're allowed to bind to devices
+    cmd.arg("--interface").arg("fff-missing0");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and verify that the client was built but couldn't connect
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("Failed to create HTTP client").not())
+        .stderr(predicate::str::contains("Connection failed"));
+    assert_eq!(mock.hits(), 0);
+}
+
+#[tokio::test]
+async fn test_compress_request() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Expect a gzipped body that decompresses to the original
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/")
+            .header("content-encoding", "gzip")
+            .matches(|req| {
+                use std::io::Read;
+
+                let body = req.body.clone().unwrap_or_default();
+                let mut decoded = String::new();
+                flate2::read::GzDecoder::new(&body[..])
+                    .read_to_string(&mut decoded)
+                    .is_ok()
+                    && decoded == "a".repeat(1000)
+                    && body.len() < 100
+            });
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Send a compressible body, gzipped
+    cmd.arg("-b")
+        .arg("a".repeat(1000))
+        .arg("--compress-request");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    // Verify that the compressed body was received
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_compress_body_file() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    // Expect a gzipped body that decompresses to the file's contents
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/")
+            .header("content-encoding", "gzip")
+            .matches(|req| {
+                use std::io::Read;
+
+                let body = req.body.clone().unwrap_or_default();
+                let mut decoded = Vec::new();
+                flate2::read::GzDecoder::new(&body[..])
+                    .read_to_end(&mut decoded)
+                    .is_ok()
+                    && decoded == b"a".repeat(200_000)
+                    && body.len() < 10_000
+            });
+        then.status(200);
+    });
+
+    // Write a body larger than one read chunk
+    let temp_dir = TempDir::new().unwrap();
+    let body_path = temp_dir.path().join("body.txt");
+    fs::write(&body_path, "a".repeat(200_000)).unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Stream the body from the file, gzipped
+    cmd.arg("--body-file")
+        .arg(&body_path)
+        .arg("--compress-request");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and check it succeeded
+    cmd.assert().success();
+
+    // Verify that the compressed body was received
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_json_errors() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Report results and errors as JSON
+    cmd.arg("--json");
+
+    // Provide a working URL and one on a closed port via stdin
+    cmd.write_stdin(format!("{}\nhttp://127.0.0.1:1/\n", server.url("/")));
+
+    // Run the command and check it succeeded
+    let output = cmd.assert().success().get_output().clone();
+
+    // Verify that the result line is JSON
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let result: serde_json::Value = serde_json::from_str(stdout.trim()).unwrap();
+    assert_eq!(result["url"], server.url("/"));
+    assert_eq!(result["status"], 200);
+    assert_eq!(result["outcome"], "seen");
+
+    // Verify that the failure is reported as JSON on stderr
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error: serde_json::Value = serde_json::from_str(stderr.lines().next().unwrap()).unwrap();
+    assert_eq!(error["url"], "http://127.0.0.1:1/");
+    assert_eq!(error["kind"], "connect");
+    assert!(error["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Connection failed: "));
+
+    // Verify that the closing summary is JSON too
+    let summary: serde_json::Value = serde_json::from_str(stderr.lines().last().unwrap()).unwrap();
+    assert_eq!(summary["failed_requests"]["connect"], 1);
+}
+
+#[tokio::test]
+async fn test_input_regex_filters() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let in_scope = server.mock(|when, then| {
+        when.method(GET).path("/api/users");
+        then.status(200);
+    });
+    let excluded = server.mock(|when, then| {
+        when.method(GET).path("/api/logout");
+        then.status(200);
+    });
+    let out_of_scope = server.mock(|when, then| {
+        when.method(GET).path("/static/app.js");
+        then.status(200);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Only request API URLs, except logout, and drop comments and blank lines
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--include-regex")
+        .arg("/api/")
+        .arg("--exclude-regex")
+        .arg("logout");
+
+    // Provide a mix of lines via stdin
+    cmd.write_stdin(format!(
+        "# wordlist\n\n{}\n{}\n{}\n",
+        server.url("/api/users"),
+        server.url("/api/logout"),
+        server.url("/static/app.js")
+    ));
+
+    // Run the command and verify that no bad lines were treated as URLs
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("Invalid URL").not());
+
+    // Verify that only the in-scope URL was requested
+    in_scope.assert();
+    assert_eq!(excluded.hits(), 0);
+    assert_eq!(out_of_scope.hits(), 0);
+}
+
+#[tokio::test]
+async fn test_per_host_file() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/one");
+        then.status(200).body("first body");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/two");
+        then.status(404).body("second body");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses into one file per host
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--per-host-file");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{}\n{}\n", server.url("/one"), server.url("/two")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that the output holds just the host file
+    let entries: Vec<String> = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(entries, vec!["127.0.0.1.txt"]);
+
+    // Verify that both responses were appended with their URLs
+    let contents = fs::read_to_string(temp_dir.path().join("127.0.0.1.txt")).unwrap();
+    assert!(contents.contains(&format!("==== GET {} (200) ====", server.url("/one"))));
+    assert!(contents.contains(&format!("==== GET {} (404) ====", server.url("/two"))));
+    assert!(contents.contains("first body"));
+    assert!(contents.contains("second body"));
+}
+
+#[tokio::test]
+async fn test_detect_waf() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(403).header("cf-ray", "8c2b1f0e9a7d1234-AMS");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Flag WAF responses
+    cmd.arg("--detect-waf");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/")));
+
+    // Run the command and verify that the WAF is named
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("[WAF: cloudflare]"));
+}
+
+#[tokio::test]
+async fn test_extract_text() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body(
+            "<html><head><title>Admin</title><style>p { color: red; }</style>\n\
+             <script>var secret = 1;</script></head>\n\
+             <body><h1>Welcome</h1><p>Fish &amp; <b>chips</b></p><!-- hidden --></body></html>",
+        );
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses, with their text
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--extract-text");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Find the saved text file
+    let text_file = fs::read_dir(temp_dir.path().join("127.0.0.1").join("root"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "txt"))
+        .expect("no .txt file was saved");
+    let text = fs::read_to_string(text_file).unwrap();
+
+    // Verify that only the visible text remains
+    assert_eq!(text, "Admin\nWelcome Fish & chips\n");
+}
+
+#[tokio::test]
+async fn test_extract_matches() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/contact");
+        then.status(200)
+            .body("Write to alice@example.com or bob@example.com, or alice@example.com again.");
+    });
+
+    // Use a temporary directory for the results file
+    let temp_dir = TempDir::new().unwrap();
+    let results = temp_dir.path().join("emails.tsv");
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Extract email addresses from each body
+        cmd.arg("--extract")
+            .arg(r"[a-z]+@[a-z]+\.com")
+            .arg("--extract-output")
+            .arg(&results);
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/contact")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that each distinct match was written once with its URL
+    let url = server.url("/contact");
+    assert_eq!(
+        fs::read_to_string(results).unwrap(),
+        format!("{}\talice@example.com\n{}\tbob@example.com\n", url, url)
+    );
+}
+
+#[tokio::test]
+async fn test_save_cert() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    // Serve HTTPS with a self-signed certificate from the fixtures
+    let cert = CertificateDer::from_pem_slice(include_bytes!("fixtures/test-cert.pem")).unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("fixtures/test-key.pem")).unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(vec![cert], key)
+    .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(mut tls) = acceptor.accept(stream).await {
+                    let mut buf = [0u8; 8192];
+                    let _ = tls.read(&mut buf).await;
+                    let _ = tls
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecure")
+                        .await;
+                    let _ = tls.shutdown().await;
+                }
+            });
+        }
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Save all responses and their certificates
+    cmd.arg("-o")
+        .arg(temp_dir.path())
+        .arg("-S")
+        .arg("--save-cert");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("https://{}/\n", addr));
+
+    // Run the command off the runtime the server is on
+    let assert = tokio::task::spawn_blocking(move || cmd.assert())
+        .await
+        .unwrap();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Saved (200)"));
+
+    // Find the saved certificate file
+    let cert_file = fs::read_dir(temp_dir.path().join("127.0.0.1").join("root"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "cert"))
+        .expect("no .cert file was saved");
+    let cert = fs::read_to_string(cert_file).unwrap();
+
+    // Verify that the certificate details were recorded
+    assert!(cert.contains("Subject: CN=fff-test, O=fff\n"), "{}", cert);
+    assert!(cert.contains("Issuer: CN=fff-test, O=fff\n"));
+    assert!(cert.contains("Serial: 572f066699b39420415b3b7bd291428bcf8582b3\n"));
+    assert!(cert.contains("Not after: 2126-09-23 19:25:34 UTC\n"));
+    assert!(cert.contains("SAN: DNS:localhost, IP:127.0.0.1\n"));
+    assert!(cert.contains("-----BEGIN CERTIFICATE-----\n"));
+}
+
+#[test]
+fn test_queue_backpressure() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    // Serve nothing, so every request stays in flight
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut held = Vec::new();
+        for stream in listener.incoming() {
+            held.push(stream);
+        }
+    });
+
+    // Start fff with a small pool and queue
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("fff"))
+        .args(["-d", "0", "-c", "4", "--queue-size", "8", "--max-time", "2"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Feed far more URLs than fit anywhere, counting how many get taken
+    let mut stdin = child.stdin.take().unwrap();
+    let written = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&written);
+    let line = format!("http://{}/\n", addr);
+    std::thread::spawn(move || {
+        for _ in 0..1_000_000 {
+            if stdin.write_all(line.as_bytes()).is_err() {
+                break;
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    // Once the pool and queue are full, fff stops reading and the writer
+    // blocks on the pipe
+    std::thread::sleep(Duration::from_secs(1));
+    let taken = written.load(Ordering::SeqCst);
+    child.wait().unwrap();
+
+    // Verify that only the pool, the queue and the OS pipe and read buffers
+    // ever held input
+    assert!(
+        taken < 20_000,
+        "{} lines were accepted while requests were stuck",
+        taken
+    );
+}
+
+#[tokio::test]
+async fn test_json_file() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/a");
+        then.status(200);
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/b");
+        then.status(404);
+    });
+
+    // Use a temporary directory for the results file
+    let temp_dir = TempDir::new().unwrap();
+    let results = temp_dir.path().join("results.json");
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Collect results into one JSON file
+        cmd.arg("--json-file").arg(&results);
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{}\n{}\n", server.url("/a"), server.url("/b")));
+
+        // Run the command and check that normal output is unchanged
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(server.url("/a")));
+    }
+
+    // Verify that the file is a JSON array with one element per URL
+    let results: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(results).unwrap()).unwrap();
+    let mut statuses: Vec<(String, u64)> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            (
+                r["url"].as_str().unwrap().to_string(),
+                r["status"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    statuses.sort();
+    assert_eq!(
+        statuses,
+        vec![(server.url("/a"), 200), (server.url("/b"), 404)]
+    );
+}
+
+#[tokio::test]
+async fn test_both_methods() {
+    // Start a mock server that sends a different header to HEAD requests
+    let server = MockServer::start_async().await;
+
+    let head = server.mock(|when, then| {
+        when.method(httpmock::Method::HEAD).path("/");
+        then.status(200).header("X-Head-Only", "yes");
+    });
+    let get = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("full body");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save both a HEAD and a GET of each URL
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--both-methods");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and verify that both responses are reported
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved (200) [HEAD]"))
+            .stdout(predicate::str::contains("Saved (200) [GET]"));
+    }
+
+    // Verify that both methods hit the server
+    head.assert();
+    get.assert();
+
+    // Verify that the captures are saved separately and tagged by method
+    let saved: Vec<String> = fs::read_dir(temp_dir.path().join("127.0.0.1").join("root"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    let mut tags: Vec<&str> = saved.iter().map(|n| n.split_once('.').unwrap().1).collect();
+    tags.sort();
+    assert_eq!(tags, vec!["get.body", "get.headers", "head.headers"]);
+
+    let head_file = saved.iter().find(|n| n.ends_with("head.headers")).unwrap();
+    let head_headers = fs::read_to_string(
+        temp_dir
+            .path()
+            .join("127.0.0.1")
+            .join("root")
+            .join(head_file),
+    )
+    .unwrap();
+    assert!(head_headers.starts_with("HEAD "));
+    assert!(head_headers.contains("< x-head-only: yes"));
+}
+
+#[tokio::test]
+async fn test_strip_header() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .header("X-Cache", "HIT from edge-42")
+            .header("Content-Type", "text/plain");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses without the volatile headers
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--strip-header")
+            .arg("X-Cache")
+            .arg("--strip-header")
+            .arg("date");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Find the saved headers file
+    let headers_file = fs::read_dir(temp_dir.path().join("127.0.0.1").join("root"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "headers"))
+        .unwrap();
+    let headers = fs::read_to_string(headers_file).unwrap();
+
+    // Verify that the stripped headers are gone and the rest remain
+    assert!(!headers.contains("< x-cache:"), "headers were: {}", headers);
+    assert!(!headers.contains("< date:"));
+    assert!(headers.contains("< content-type: text/plain"));
+}
+
+#[tokio::test]
+async fn test_canonical_json_dedupe() {
+    // Start a mock server returning the same JSON with keys in different orders
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/a");
+        then.status(200)
+            .body(r#"{"id": 1, "tags": ["x"], "name": "fff"}"#);
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/b");
+        then.status(200)
+            .body(r#"{"name":"fff","id":1,"tags":["x"]}"#);
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses, deduping JSON bodies by content
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--dedupe-body")
+            .arg("--canonical-json");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{}\n{}\n", server.url("/a"), server.url("/b")));
+
+        // Run the command and verify that one of them was a duplicate
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("[duplicate]").count(1));
+    }
+
+    // Verify that only one body was saved, and saved as received
+    let host_dir = temp_dir.path().join("127.0.0.1");
+    let saved: Vec<PathBuf> = ["a", "b"]
+        .iter()
+        .map(|dir| host_dir.join(dir))
+        .filter(|dir| dir.exists())
+        .collect();
+    assert_eq!(saved.len(), 1);
+    let body = fs::read_dir(&saved[0])
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "body"))
+        .unwrap();
+    assert!(fs::read_to_string(body).unwrap().contains("\"id\""));
+}
+
+#[tokio::test]
+async fn test_comment_lines_skipped() {
+    // Start a mock server with two paths
+    let server = MockServer::start_async().await;
+
+    let first = server.mock(|when, then| {
+        when.method(GET).path("/first");
+        then.status(200).body("first");
+    });
+    let second = server.mock(|when, then| {
+        when.method(GET).path("/second");
+        then.status(200).body("second");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Provide the URLs via stdin, with comment lines interleaved
+    cmd.write_stdin(format!(
+        "# targets for today\n{}\n  # {}\n{}\n",
+        server.url("/first"),
+        server.url("/disabled"),
+        server.url("/second")
+    ));
+
+    // Run the command and verify that no comment was requested
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("#").not())
+        .stderr(predicate::str::contains("#").not());
+
+    first.assert();
+    second.assert();
+}
+
+#[tokio::test]
+async fn test_allow_comments_switch() {
+    // A bare --allow-comments is accepted, and false turns comment skipping off
+    for (args, expected) in [
+        (vec!["--allow-comments"], 0),
+        (vec!["--allow-comments", "false"], 1),
+    ] {
+        // Start a mock server
+        let server = MockServer::start_async().await;
+
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).body("content");
+        });
+
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+        cmd.args(&args);
+
+        // Provide a line that is a comment, or a fragment of the base URL
+        cmd.arg("--base").arg(server.url("/"));
+        cmd.write_stdin("#disabled\n");
+
+        // Run the command and verify whether the line was requested
+        cmd.assert().success();
+        mock.assert_hits(expected);
+    }
+}
+
+#[tokio::test]
+async fn test_retry_empty() {
+    // Start a server that answers with an empty body before the real one
+    let empty = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let ok = "HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\ncontent";
+    let (addr, requests) = scripted_server(vec![empty, ok]);
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save the response, retrying while it is empty
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--retry-empty")
+            .arg("--retries")
+            .arg("3");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("http://{}/\n", addr));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    // Verify that the request was sent again and the non-empty body was saved
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    let url = reqwest::Url::parse(&format!("http://{}/", addr)).unwrap();
+    let expected_dir = temp_dir.path().join("127.0.0.1").join(normalise_path(&url));
+    let body = fs::read_dir(expected_dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "body"))
+        .unwrap();
+    assert_eq!(fs::read_to_string(body).unwrap(), "content");
+}
+
+#[tokio::test]
+async fn test_detect_extension() {
+    // Start a mock server returning JSON
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/api");
+        then.status(200)
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(r#"{"ok":true}"#);
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save the response with a detected extension
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--detect-extension");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/api")));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    // Verify that the body was saved as .json rather than .body
+    let extensions: Vec<String> = fs::read_dir(temp_dir.path().join("127.0.0.1").join("api"))
+        .unwrap()
+        .map(|e| {
+            let path = e.unwrap().path();
+            path.extension().unwrap().to_string_lossy().into_owned()
+        })
+        .collect();
+    assert!(extensions.contains(&"json".to_string()));
+    assert!(!extensions.contains(&"body".to_string()));
+}
+
+#[tokio::test]
+async fn test_circuit_break() {
+    // Start a server that is always unavailable
+    let unavailable =
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let (addr, requests) = scripted_server(vec![unavailable]);
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Give up on the host after two 503s, one request at a time
+    cmd.arg("--circuit-break")
+        .arg("503:2")
+        .arg("-c")
+        .arg("1")
+        .arg("-d")
+        .arg("0");
+
+    // Provide the URLs via stdin
+    let urls: String = (0..5).map(|i| format!("http://{}/{}\n", addr, i)).collect();
+    cmd.write_stdin(urls);
+
+    // Run the command and verify that the remaining URLs were skipped
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("answered 503 2 times"))
+        .stderr(predicate::str::contains("Skipped for").count(3));
+
+    // Verify that the host was only requested twice
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_json_host_notices() {
+    // Start a server that rate limits once, then is always unavailable
+    let too_many =
+        "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let unavailable =
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let (addr, _) = scripted_server(vec![too_many, unavailable]);
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Back off on 429s and give up on the host after two 503s, reporting as JSON
+    cmd.arg("--json")
+        .arg("--adaptive-delay")
+        .arg("--circuit-break")
+        .arg("503:2")
+        .arg("-c")
+        .arg("1")
+        .arg("-d")
+        .arg("0");
+
+    // Provide the URLs via stdin
+    let urls: String = (0..3).map(|i| format!("http://{}/{}\n", addr, i)).collect();
+    cmd.write_stdin(urls);
+
+    // Run the command and check it succeeded
+    let output = cmd.assert().success().get_output().clone();
+
+    // Verify that every stderr line is JSON, including both notices
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|_| panic!("not JSON: {}", line)))
+        .collect();
+    let notices: Vec<&str> = lines
+        .iter()
+        .filter_map(|line| line["notice"].as_str())
+        .collect();
+    assert_eq!(notices, vec!["rate_limited", "circuit_open"]);
+}
+
+#[tokio::test]
+async fn test_report() {
+    // Start a mock server with a found and a missing page
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/found");
+        then.status(200).body("found");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/missing");
+        then.status(404).body("missing");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses and write the report
+        cmd.arg("-o").arg(temp_dir.path()).arg("-S").arg("--report");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/found"),
+            server.url("/missing")
+        ));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    // Verify that the report links each saved response with its status class
+    let html = fs::read_to_string(temp_dir.path().join("index.html")).unwrap();
+    assert!(html.contains("class=\"status success\">200<"));
+    assert!(html.contains("class=\"status client-error\">404<"));
+
+    let links: Vec<&str> = html
+        .split("href=\"")
+        .skip(1)
+        .map(|rest| rest.split('"').next().unwrap())
+        .collect();
+    assert_eq!(links.len(), 2);
+    for link in links {
+        assert!(temp_dir.path().join(link).is_file(), "{} not saved", link);
+    }
+}
+
+#[tokio::test]
+async fn test_http_version_dir() {
+    // Serve HTTP/2 over TLS
+    let addr = h2_server(
+        || http::Response::builder().status(200).body(()).unwrap(),
+        b"over h2",
+    )
+    .await;
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Save all responses under their HTTP version
+    cmd.arg("-o")
+        .arg(temp_dir.path())
+        .arg("-S")
+        .arg("--http-version-dir");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("https://{}/h2\n", addr));
+
+    // Run the command off the runtime the server is on
+    let assert = tokio::task::spawn_blocking(move || cmd.assert())
+        .await
+        .unwrap();
+    assert
+        .success()
+        .stdout(predicate::str::contains("Saved (200)"));
+
+    // Verify that the response was saved under an http2 segment
+    let expected_dir = temp_dir.path().join("127.0.0.1").join("http2").join("h2");
+    assert!(expected_dir.is_dir(), "Expected directory not found");
+}
+
+#[tokio::test]
+async fn test_base_url() {
+    // Start a mock server with two paths
+    let server = MockServer::start_async().await;
+
+    let mock_a = server.mock(|when, then| {
+        when.method(GET).path("/app/a");
+        then.status(200).body("a");
+    });
+    let mock_b = server.mock(|when, then| {
+        when.method(GET).path("/b");
+        then.status(200).body("b");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Resolve the input against a base URL
+    cmd.arg("--base").arg(server.url("/app/"));
+
+    // Provide a relative path, an absolute path and a full URL via stdin
+    cmd.write_stdin(format!("a\n/b\n{}\n", server.url("/b")));
+
+    // Run the command and verify the resolved URLs are reported
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(server.url("/app/a")))
+        .stdout(predicate::str::contains(server.url("/b")));
+
+    // Verify that the resolved URLs were requested
+    mock_a.assert();
+    mock_b.assert_hits(2);
+}
+
+#[tokio::test]
+async fn test_decode_charset() {
+    // Start a mock server returning a Latin-1 page
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/menu");
+        then.status(200)
+            .header("Content-Type", "text/plain; charset=ISO-8859-1")
+            .body(b"Today: caf\xe9 au lait");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Match a UTF-8 string against the decoded body
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-M")
+            .arg("café")
+            .arg("--decode-charset");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/menu")));
+
+        // Run the command and verify that the body matched
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Saved (200)"));
+    }
+
+    // Verify that the body was saved as UTF-8 and the original charset noted
+    let expected_dir = temp_dir.path().join("127.0.0.1").join("menu");
+    let mut body = None;
+    let mut headers = None;
+    for entry in fs::read_dir(&expected_dir).unwrap() {
+        let path = entry.unwrap().path();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("body") => body = Some(fs::read_to_string(&path).unwrap()),
+            Some("headers") => headers = Some(fs::read_to_string(&path).unwrap()),
+            _ => {}
+        }
+    }
+    assert_eq!(body.unwrap(), "Today: café au lait");
+    assert!(headers
+        .unwrap()
+        .contains("< X-FFF-Original-Charset: windows-1252"));
+}
+
+#[tokio::test]
+async fn test_pool_max_idle_per_host() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/pooled");
+        then.status(200).body("pooled");
+    });
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Keep up to four idle connections to the host
+        cmd.arg("-k").arg("--pool-max-idle-per-host").arg("4");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{0}\n{0}\n", server.url("/pooled")));
+
+        // Run the command and verify the client was built and used
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("200").count(2));
+    }
+
+    mock.assert_hits(2);
+
+    // Prepare a command without keep-alive
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+    cmd.arg("--pool-max-idle-per-host").arg("4");
+
+    // Run the command and verify the combination is rejected
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--keep-alive"));
+}
+
+#[tokio::test]
+async fn test_match_report() {
+    // Start a mock server with a matching and a non-matching page
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/secret");
+        then.status(200).body("api_key=abc123");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/public");
+        then.status(200).body("nothing here");
+    });
+
+    // Use a temporary output directory and report file
+    let temp_dir = TempDir::new().unwrap();
+    let report = temp_dir.path().join("matches.txt");
+    let output = temp_dir.path().join("out");
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Report matches without saving them
+        cmd.arg("-o")
+            .arg(&output)
+            .arg("-M")
+            .arg("api_key")
+            .arg("--match-report")
+            .arg(&report);
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/secret"),
+            server.url("/public")
+        ));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    // Verify that only the matching URL was reported
+    let reported = fs::read_to_string(&report).unwrap();
+    assert_eq!(reported, format!("{}\n", server.url("/secret")));
+
+    // Verify that no body was saved without -S
+    assert!(!output.join("127.0.0.1").exists());
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Report matches and save the 200 responses among them
+        cmd.arg("-o")
+            .arg(&output)
+            .arg("-M")
+            .arg("api_key")
+            .arg("--match-report")
+            .arg(&report)
+            .arg("-s")
+            .arg("200");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/secret"),
+            server.url("/public")
+        ));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    // Verify that only the matching body was saved with -s
+    let saved = fs::read_dir(output.join("127.0.0.1")).unwrap().count();
+    assert_eq!(saved, 1);
+}
+
+#[tokio::test]
+async fn test_color_control() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200).body("Hello, world!");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    for (color, colored) in [("auto", false), ("never", false), ("always", true)] {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Set the color mode; stdout is captured, so never a terminal
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("--color")
+            .arg(color)
+            .env_remove("NO_COLOR");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/")));
+
+        // Run the command and verify escape codes appear only when forced
+        let output = cmd.assert().success().get_output().stdout.clone();
+        let stdout = String::from_utf8(output).unwrap();
+        assert!(stdout.contains("200"), "{}: {}", color, stdout);
+        assert_eq!(stdout.contains('\x1b'), colored, "{}: {:?}", color, stdout);
+    }
+}
+
+#[tokio::test]
+async fn test_json_path_value() {
+    // Start a mock server with JSON and non-JSON responses
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/ok");
+        then.status(200).body(r#"{"status": "ok"}"#);
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/failed");
+        then.status(200).body(r#"{"status": "error"}"#);
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/html");
+        then.status(200).body("<html>status ok</html>");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save only responses whose status field is "ok"
+        cmd.arg("-o")
+            .arg(temp_dir.path().join("out"))
+            .arg("--json-path")
+            .arg("$.status")
+            .arg("--json-value")
+            .arg("ok");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n{}\n",
+            server.url("/ok"),
+            server.url("/failed"),
+            server.url("/html")
+        ));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that only the matching response was saved
+    let host_dir = temp_dir.path().join("out").join("127.0.0.1");
+    assert!(host_dir.join("ok").is_dir());
+    assert!(!host_dir.join("failed").exists());
+    assert!(!host_dir.join("html").exists());
+}
+
+#[tokio::test]
+async fn test_body_file_streamed() {
+    // Write a large request body to a temporary file
+    let temp_dir = TempDir::new().unwrap();
+    let body_path = temp_dir.path().join("upload.txt");
+    let content: String = (0..100_000).map(|i| format!("line {}\n", i)).collect();
+    fs::write(&body_path, &content).unwrap();
+
+    // Start a mock server expecting the whole file, sent chunked
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/upload")
+            .header("transfer-encoding", "chunked")
+            .body(content.clone());
+        then.status(201).body("stored");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Stream the body from the file
+    cmd.arg("--body-file").arg(&body_path);
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/upload")));
+
+    // Run the command and verify the upload was accepted
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("201"));
+
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_rendered_body_names() {
+    // Start a mock server accepting any POST
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(POST).path("/submit");
+        then.status(200).body("accepted");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // The body template renders differently per request
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("-b")
+            .arg("id={random}");
+
+        // Provide the same URL twice via stdin
+        cmd.write_stdin(format!("{0}\n{0}\n", server.url("/submit")));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    // Verify that each rendered body got its own file name
+    let bodies = fs::read_dir(temp_dir.path().join("127.0.0.1").join("submit"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".body"))
+        .count();
+    assert_eq!(bodies, 2);
+}
+
+#[tokio::test]
+async fn test_doh_resolver() {
+    // Start a mock target server
+    let server = MockServer::start_async().await;
+
+    let target = server.mock(|when, then| {
+        when.method(GET).path("/resolved");
+        then.status(200).body("resolved");
+    });
+
+    // Start a mock DoH server resolving a made-up name to the target
+    let doh = MockServer::start_async().await;
+
+    let a_record = doh.mock(|when, then| {
+        when.method(GET)
+            .path("/dns-query")
+            .query_param("name", "fff.invalid")
+            .query_param("type", "1")
+            .header("accept", "application/dns-json");
+        then.status(200)
+            .header("Content-Type", "application/dns-json")
+            .body(r#"{"Status":0,"Answer":[{"name":"fff.invalid","type":1,"TTL":60,"data":"127.0.0.1"}]}"#);
+    });
+    doh.mock(|when, then| {
+        when.method(GET)
+            .path("/dns-query")
+            .query_param("type", "28");
+        then.status(200)
+            .header("Content-Type", "application/dns-json")
+            .body(r#"{"Status":0}"#);
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Resolve names through the DoH server
+    cmd.arg("--doh").arg(doh.url("/dns-query"));
+
+    // Provide a URL on the made-up name via stdin
+    cmd.write_stdin(format!(
+        "http://fff.invalid:{}/resolved\n",
+        server.address().port()
+    ));
+
+    // Run the command and verify the request succeeded
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200"));
+
+    // Verify that the name was looked up and the target requested
+    a_record.assert();
+    target.assert();
+}
+
+#[tokio::test]
+async fn test_save_bytes() {
+    // Start a mock server returning a long body
+    let server = MockServer::start_async().await;
+
+    let long_body = "0123456789".repeat(1000);
+    server.mock(|when, then| {
+        when.method(GET).path("/large");
+        then.status(200).body(long_body.clone());
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save only a preview of the body
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--save-bytes")
+            .arg("10");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/large")));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    // Verify that the body was cut short and its full length recorded
+    let expected_dir = temp_dir.path().join("127.0.0.1").join("large");
+    for entry in fs::read_dir(&expected_dir).unwrap() {
+        let path = entry.unwrap().path();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("body") => assert_eq!(fs::read(&path).unwrap(), b"0123456789"),
+            Some("headers") => assert!(fs::read_to_string(&path)
+                .unwrap()
+                .contains("< X-FFF-Body-Length: 10000")),
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_methods_file() {
+    // Start a mock server answering GET and PROPFIND
+    let server = MockServer::start_async().await;
+
+    let get = server.mock(|when, then| {
+        when.method(GET).path("/dav");
+        then.status(200).body("listing");
+    });
+    let propfind = server.mock(|when, then| {
+        when.matches(|req| req.method == "PROPFIND" && req.path == "/dav");
+        then.status(207).body("<multistatus/>");
+    });
+
+    // List the methods in a file
+    let temp_dir = TempDir::new().unwrap();
+    let methods_path = temp_dir.path().join("methods.txt");
+    fs::write(&methods_path, "GET\n# WebDAV\nPROPFIND\n").unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Fan out over the listed methods
+    cmd.arg("--methods-file").arg(&methods_path);
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/dav")));
+
+    // Run the command and verify both responses are reported
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("200 [GET]"))
+        .stdout(predicate::str::contains("207 [PROPFIND]"));
+
+    // Verify that both methods hit the server
+    get.assert();
+    propfind.assert();
+}
+
+#[tokio::test]
+async fn test_slow_threshold() {
+    // Start a mock server with a fast and a slow endpoint
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/fast");
+        then.status(200).body("fast");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/slow");
+        then.status(200)
+            .body("slow")
+            .delay(Duration::from_millis(600));
+    });
+
+    // Log slow responses to a temporary file
+    let temp_dir = TempDir::new().unwrap();
+    let slow_log = temp_dir.path().join("slow.txt");
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Flag responses slower than 300ms
+        cmd.arg("--slow-threshold")
+            .arg("300")
+            .arg("--slow-log")
+            .arg(&slow_log);
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/fast"),
+            server.url("/slow")
+        ));
+
+        // Run the command and verify that only the slow response is flagged
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_match(r"/slow 200 \[SLOW \d+ms\]").unwrap())
+            .stdout(predicate::str::contains("SLOW").count(1));
+    }
+
+    // Verify that the slow response was logged
+    let logged = fs::read_to_string(&slow_log).unwrap();
+    assert!(logged.starts_with(&format!("{} ", server.url("/slow"))));
+    assert_eq!(logged.lines().count(), 1);
+}
+
+#[tokio::test]
+async fn test_request_file() {
+    // Start a mock server expecting the request exactly as written
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(POST)
+            .path("/api/users/42")
+            .header("x-custom", "kept as written")
+            .header("content-type", "application/json")
+            .body(r#"{"id": "42", "note": "raw"}"#);
+        then.status(200).body("user 42");
+    });
+
+    // Write a raw request with the payload slot in the path and the body
+    let temp_dir = TempDir::new().unwrap();
+    let request = temp_dir.path().join("request.txt");
+    fs::write(
+        &request,
+        "POST /api/users/{FUZZ} HTTP/1.1\r\n\
+         Host: api.fff.test\r\n\
+         X-Custom: kept as written\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: 999\r\n\
+         \r\n\
+         {\"id\": \"{FUZZ}\", \"note\": \"raw\"}",
+    )
+    .unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Send the raw request to the mock server
+    cmd.arg("--request-file")
+        .arg(&request)
+        .arg("--base")
+        .arg(server.url("/"));
+
+    // Provide the payload via stdin
+    cmd.write_stdin("42\n");
+
+    // Run the command and verify the request was accepted
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{} 200",
+            server.url("/api/users/42")
+        )));
+
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_request_file_raw_scheme() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/raw").header("x-token", "abc");
+        then.status(200).body("raw");
+    });
+
+    // Write a raw request with the payload slot in a header
+    let temp_dir = TempDir::new().unwrap();
+    let request = temp_dir.path().join("request.txt");
+    fs::write(
+        &request,
+        format!(
+            "GET /raw HTTP/1.1\r\nHost: {}\r\nX-Token: {{FUZZ}}\r\n\r\n",
+            server.address()
+        ),
+    )
+    .unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Send the raw request to its Host header over plain http
+    cmd.arg("--request-file")
+        .arg(&request)
+        .arg("--raw-scheme")
+        .arg("http")
+        .arg("--json");
+
+    // Provide a valid header value and an invalid one via stdin
+    cmd.write_stdin("abc\n\x7f\n");
+
+    // Run the command and verify the invalid line was reported as JSON
+    let output = cmd.assert().success().get_output().clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let failure: serde_json::Value = serde_json::from_str(stderr.lines().next().unwrap()).unwrap();
+    assert_eq!(failure["kind"], "invalid_request");
+    assert_eq!(failure["url"], "line 2");
+
+    // Verify that the valid line was sent over http
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_case_variant() {
+    // Start a mock server that only serves the exact path, and blocks other casings
+    let server = MockServer::start_async().await;
+
+    let exact = server.mock(|when, then| {
+        when.method(GET).matches(|req| req.path == "/admin/panel");
+        then.status(200).body("panel");
+    });
+    let recased = server.mock(|when, then| {
+        when.method(GET).matches(|req| {
+            req.path != "/admin/panel" && req.path.eq_ignore_ascii_case("/admin/panel")
+        });
+        then.status(403).body("blocked");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Request a recased variant of each URL too
+    cmd.arg("-d").arg("0").arg("--case-variant");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/admin/panel")));
+
+    // Run the command and verify the difference was reported
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{} 200",
+            server.url("/admin/panel")
+        )))
+        .stdout(predicate::str::contains(format!(
+            "[case differs: 403 vs 200 for {}]",
+            server.url("/admin/panel")
+        )));
+
+    // Verify that both the original and a recased path were requested
+    exact.assert_hits(1);
+    recased.assert_hits(1);
+}
+
+#[tokio::test]
+async fn test_sort_query() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/search");
+        then.status(200).body("results");
+    });
+    let sorted = server.mock(|when, then| {
+        when.method(GET).path("/tags");
+        then.status(200).body("tags");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Canonicalize parameter order
+    cmd.arg("-d").arg("0").arg("--sort-query");
+
+    // Provide the same targets with their parameters in different orders
+    cmd.write_stdin(format!(
+        "{}\n{}\n{}\n",
+        server.url("/search?a=1&b=2"),
+        server.url("/search?b=2&a=1"),
+        server.url("/tags?t=y&s=1&t=x")
+    ));
+
+    // Run the command and verify the URLs were printed in sorted form
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(server.url("/search?a=1&b=2")))
+        .stdout(predicate::str::contains(server.url("/search?b=2&a=1")).not())
+        .stdout(predicate::str::contains(server.url("/tags?s=1&t=y&t=x")));
+
+    // Verify that the equivalent URLs were requested once
+    mock.assert_hits(1);
+    sorted.assert_hits(1);
+}
+
+#[tokio::test]
+async fn test_sort_query_relative() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/a");
+        then.status(200).body("results");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Canonicalize parameter order of relative URLs
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--sort-query")
+        .arg("--base")
+        .arg(server.url("/"));
+
+    // Provide the same target with its parameters in different orders
+    cmd.write_stdin("/a?b=1&a=2\n/a?a=2&b=1\n");
+
+    // Run the command and verify the URL was printed in sorted form
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(server.url("/a?a=2&b=1")));
+
+    // Verify that the equivalent URLs were requested once
+    mock.assert_hits(1);
+}
+
+#[tokio::test]
+async fn test_recursive_listing() {
+    // Start a mock server with an open directory two levels deep
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/pub/");
+        then.status(200).body(
+            "<html><head><title>Index of /pub</title></head><body><h1>Index of /pub</h1>\
+             <a href=\"?C=N;O=D\">Name</a>\
+             <a href=\"/\">Parent Directory</a>\
+             <a href=\"sub/\">sub/</a></body></html>",
+        );
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/pub/sub/");
+        then.status(200).body(
+            "<html><head><title>Index of /pub/sub/</title></head><body>\
+             <a href=\"../\">../</a>\
+             <a href=\"file.txt\">file.txt</a></body></html>",
+        );
+    });
+    let file = server.mock(|when, then| {
+        when.method(GET).path("/pub/sub/file.txt");
+        then.status(200).body("found me");
+    });
+    let parent = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200)
+            .body("<html><title>Index of /</title></html>");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Follow directory listings from the top one
+    cmd.arg("-d").arg("0").arg("--recursive");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/pub/")));
+
+    // Run the command and verify the nested file was fetched
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{} 200",
+            server.url("/pub/sub/file.txt")
+        )));
+
+    // Verify that each entry was fetched once and the parent never was
+    file.assert_hits(1);
+    parent.assert_hits(0);
+}
+
+#[tokio::test]
+async fn test_recursive_filters() {
+    // Start a mock server with a listing of one endpoint under two queries and a secret
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/pub/");
+        then.status(200).body(
+            "<html><head><title>Index of /pub</title></head><body>\
+             <a href=\"a.txt?x=1\">a.txt</a>\
+             <a href=\"a.txt?x=2\">a.txt</a>\
+             <a href=\"secret.txt\">secret.txt</a></body></html>",
+        );
+    });
+    let endpoint = server.mock(|when, then| {
+        when.method(GET).path("/pub/a.txt");
+        then.status(200).body("a");
+    });
+    let secret = server.mock(|when, then| {
+        when.method(GET).path("/pub/secret.txt");
+        then.status(200).body("secret");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Follow listings, with the same filters as input lines
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--recursive")
+        .arg("--dedupe-path")
+        .arg("--exclude-regex")
+        .arg("secret");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/pub/")));
+
+    // Run the command and verify it succeeds
+    cmd.assert().success();
+
+    // Verify that the endpoint was fetched once and the excluded entry never was
+    endpoint.assert_hits(1);
+    secret.assert_hits(0);
+}
+
+#[tokio::test]
+async fn test_recursive_max_depth() {
+    // Start a mock server whose listings nest forever, each linking to its
+    // child and to its grandchild, so entries are reached along two paths
+    let server = MockServer::start_async().await;
+
+    let listings = server.mock(|when, then| {
+        when.method(GET)
+            .path_matches(Regex::new(r"^/loop/(a/)*$").unwrap());
+        then.status(200).body(
+            "<html><head><title>Index of /loop</title></head><body>\
+             <a href=\"a/\">a/</a><a href=\"a/a/\">a/a/</a></body></html>",
+        );
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Follow listings two levels down
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--recursive")
+        .arg("--max-depth")
+        .arg("2");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/loop/")));
+
+    // Run the command and verify it terminates
+    cmd.timeout(Duration::from_secs(30)).assert().success();
+
+    // Verify that /loop/ and the four URLs within two levels of it were each fetched once
+    listings.assert_hits(5);
+}
+
+#[tokio::test]
+async fn test_bucket_by_type() {
+    // Start a mock server with a JSON response and an untyped PNG
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/api");
+        then.status(200)
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(r#"{"ok": true}"#);
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/logo");
+        then.status(200).body(b"\x89PNG\r\n\x1a\nrest of image");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses, grouped by type
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--bucket-by-type");
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{}\n{}\n", server.url("/api"), server.url("/logo")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that each response landed in its type's bucket
+    assert!(temp_dir.path().join("json/127.0.0.1/api").is_dir());
+    assert!(temp_dir.path().join("images/127.0.0.1/logo").is_dir());
+    assert!(!temp_dir.path().join("127.0.0.1").exists());
+}
+
+#[tokio::test]
+async fn test_ordered_output() {
+    // Start a mock server whose earlier URLs answer more slowly
+    let server = MockServer::start_async().await;
+
+    for (path, delay) in [("/first", 600), ("/second", 300), ("/third", 0)] {
+        server.mock(|when, then| {
+            when.method(GET).path(path);
+            then.status(200)
+                .body(path)
+                .delay(Duration::from_millis(delay));
+        });
+    }
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Send all requests at once but print the results in input order
+    cmd.arg("-d").arg("0").arg("--ordered");
+
+    // Provide the URLs via stdin
+    cmd.write_stdin(format!(
+        "{}\n{}\n{}\n",
+        server.url("/first"),
+        server.url("/second"),
+        server.url("/third")
+    ));
+
+    // Run the command and verify the lines follow the input, not completion
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let stdout = String::from_utf8(output).unwrap();
+    let urls: Vec<&str> = stdout
+        .lines()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(
+        urls,
+        vec![
+            server.url("/first"),
+            server.url("/second"),
+            server.url("/third")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_log_response_header() {
+    // Start a mock server that echoes a trace id on each response
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/a");
+        then.status(200).header("X-Trace-Id", "trace-a").body("a");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/b");
+        then.status(500).header("X-Trace-Id", "trace-b").body("b");
+    });
+
+    // Log the ids to a temporary file
+    let temp_dir = TempDir::new().unwrap();
+    let header_log = temp_dir.path().join("traces.txt");
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Log each response's X-Trace-Id
+        cmd.arg("--log-response-header")
+            .arg("X-Trace-Id")
+            .arg("--response-header-log")
+            .arg(&header_log);
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!("{}\n{}\n", server.url("/a"), server.url("/b")));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify that each URL was logged with its own id
+    let logged = fs::read_to_string(&header_log).unwrap();
+    let mut lines: Vec<&str> = logged.lines().collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        vec![
+            format!("{} -> trace-a", server.url("/a")),
+            format!("{} -> trace-b", server.url("/b")),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_timing_csv() {
+    // Start a mock server with a fast and a delayed endpoint
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/fast");
+        then.status(200).body("fast");
+    });
+    server.mock(|when, then| {
+        when.method(GET).path("/slow");
+        then.status(404)
+            .body("slow")
+            .delay(Duration::from_millis(300));
+    });
+
+    // Write timings to a temporary file
+    let temp_dir = TempDir::new().unwrap();
+    let csv = temp_dir.path().join("timing.csv");
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Record the timing of each response
+        cmd.arg("--timing-csv").arg(&csv);
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(format!(
+            "{}\n{}\n",
+            server.url("/fast"),
+            server.url("/slow?a=1,2")
+        ));
+
+        // Run the command and check it succeeded
+        cmd.assert().success();
+    }
+
+    // Verify the header row and one row per URL
+    let written = fs::read_to_string(&csv).unwrap();
+    let mut lines = written.lines();
+    assert_eq!(
+        lines.next(),
+        Some("url,status,dns_ms,connect_ms,ttfb_ms,total_ms")
+    );
+    let mut rows: Vec<&str> = lines.collect();
+    rows.sort();
+    assert_eq!(rows.len(), 2, "unexpected rows: {:?}", rows);
+
+    // A URL with a comma is quoted, and the delay shows up in both timings
+    let slow_prefix = format!("\"{}\",404,,,", server.url("/slow?a=1,2"));
+    let slow = rows.iter().find(|r| r.starts_with(&slow_prefix)).unwrap();
+    let times: Vec<u64> = slow[slow_prefix.len()..]
+        .split(',')
+        .map(|t| t.parse().unwrap())
+        .collect();
+    assert!(times[0] >= 300 && times[1] >= times[0], "{}", slow);
+    assert!(rows
+        .iter()
+        .any(|r| r.starts_with(&format!("{},200,,,", server.url("/fast")))));
+}
+
+#[tokio::test]
+async fn test_strict_url() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/ok");
+        then.status(200).body("ok");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Only request http and https URLs
+    cmd.arg("--strict-url");
+
+    // Provide a file URL and an http URL via stdin
+    cmd.write_stdin(format!("file:///etc/passwd\n{}\n", server.url("/ok")));
+
+    // Run the command and verify the file URL was skipped
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Unsupported scheme for file:///etc/passwd: file",
+        ))
+        .stdout(predicate::str::contains("file://").not());
+
+    // Verify that only the http URL was requested
+    mock.assert_hits(1);
+}
+
+#[tokio::test]
+async fn test_repeat() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/flaky");
+        then.status(200).body("flaky");
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Request the URL three times, saving each response
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--repeat")
+            .arg("3");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/flaky")));
+
+        // Run the command and verify every attempt is reported
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("[attempt 1]"))
+            .stdout(predicate::str::contains("[attempt 2]"))
+            .stdout(predicate::str::contains("[attempt 3]"));
+    }
+
+    // Verify that the server was hit three times and each attempt saved
+    mock.assert_hits(3);
+    let bodies = fs::read_dir(temp_dir.path().join("127.0.0.1").join("flaky"))
+        .unwrap()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "body")
+        })
+        .count();
+    assert_eq!(bodies, 3);
+}
+
+#[tokio::test]
+async fn test_accept_encoding_without_decompression() {
+    // gzip of "hello"
+    let gzipped: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xcb\x48\xcd\xc9\xc9\x07\x00\x86\xa6\x10\x36\x05\x00\x00\x00";
+
+    // Start a mock server expecting the configured Accept-Encoding
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET)
+            .path("/compressed")
+            .header("accept-encoding", "gzip");
+        then.status(200)
+            .header("Content-Encoding", "gzip")
+            .body(gzipped);
+    });
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Ask for gzip only and keep the body compressed
+        cmd.arg("-o")
+            .arg(temp_dir.path())
+            .arg("-S")
+            .arg("--accept-encoding")
+            .arg("gzip")
+            .arg("--no-auto-decompress");
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/compressed")));
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+    }
+
+    mock.assert();
+
+    // Verify that the raw gzip bytes were saved
+    let body = fs::read_dir(temp_dir.path().join("127.0.0.1").join("compressed"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "body"))
+        .unwrap();
+    assert_eq!(fs::read(body).unwrap(), gzipped);
+}
+
+#[tokio::test]
+async fn test_progress_eta() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/step");
+        then.status(200).body("step");
+    });
+
+    // Write the URLs to an input file so they can be counted
+    let temp_dir = TempDir::new().unwrap();
+    let input = temp_dir.path().join("urls.txt");
+    fs::write(&input, format!("{}\n", server.url("/step")).repeat(5)).unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Show progress, one request at a time
+    cmd.arg("-i")
+        .arg(&input)
+        .arg("--progress")
+        .arg("-c")
+        .arg("1")
+        .arg("-d")
+        .arg("50");
+
+    // Run the command and verify the progress counts up to the total with an
+    // ETA, in plain lines as stderr isn't a terminal
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::contains("1/5 (20%)\n"))
+        .stderr(predicate::str::is_match(r"5/5 \(100%\), [\d.]+/s, ETA 00:00\n").unwrap())
+        .stderr(predicate::str::contains("\x1b[K").not())
+        .stdout(predicate::str::contains("ETA").not());
+}
+
+#[tokio::test]
+async fn test_sni_override() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    // Serve HTTPS, recording the server name and Host header each client sends
+    let cert = CertificateDer::from_pem_slice(include_bytes!("fixtures/test-cert.pem")).unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("fixtures/test-key.pem")).unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(vec![cert], key)
+    .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorder = Arc::clone(&seen);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let recorder = Arc::clone(&recorder);
+            tokio::spawn(async move {
+                if let Ok(mut tls) = acceptor.accept(stream).await {
+                    let sni = tls.get_ref().1.server_name().map(str::to_string);
+                    let mut buf = [0u8; 8192];
+                    let n = tls.read(&mut buf).await.unwrap_or(0);
+                    let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                    recorder.lock().unwrap().push((sni, request));
+                    let _ = tls
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await;
+                    let _ = tls.shutdown().await;
+                }
+            });
+        }
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Override the server name
+    cmd.arg("--sni").arg("cdn.fff.test");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("https://{}/\n", addr));
+
+    // Run the command off the runtime the server is on
+    let assert = tokio::task::spawn_blocking(move || cmd.assert())
+        .await
+        .unwrap();
+    assert
+        .success()
+        .stdout(predicate::str::contains(format!("https://{}/ 200", addr)));
+
+    // Verify that the handshake used the override and Host kept the URL's host
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].0.as_deref(), Some("cdn.fff.test"));
+    assert!(seen[0].1.contains(&format!("host: {}\r\n", addr)));
+}
+
+#[tokio::test]
+async fn test_sni_reuses_connections() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    // Serve HTTPS, answering every request on a connection and counting handshakes
+    let cert = CertificateDer::from_pem_slice(include_bytes!("fixtures/test-cert.pem")).unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("fixtures/test-key.pem")).unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(vec![cert], key)
+    .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handshakes = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&handshakes);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let counter = Arc::clone(&counter);
+            tokio::spawn(async move {
+                if let Ok(mut tls) = acceptor.accept(stream).await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let mut buf = [0u8; 8192];
+                    while let Ok(n) = tls.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                        let _ = tls
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .await;
+                    }
+                }
+            });
+        }
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Override the server name, one request at a time over kept-alive connections
+    cmd.arg("--sni")
+        .arg("cdn.fff.test")
+        .arg("-k")
+        .arg("-c")
+        .arg("1")
+        .arg("-d")
+        .arg("0");
+
+    // Provide three URLs on the same host via stdin
+    let urls: String = (0..3)
+        .map(|i| format!("https://{}/{}\n", addr, i))
+        .collect();
+    cmd.write_stdin(urls);
+
+    // Run the command off the runtime the server is on
+    let assert = tokio::task::spawn_blocking(move || cmd.assert())
+        .await
+        .unwrap();
+    assert
+        .success()
+        .stdout(predicate::str::contains(" 200").count(3));
+
+    // Verify that all three requests shared one TLS connection
+    assert_eq!(handshakes.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_sni_with_resolve() {
+    // Serve HTTPS on a local port
+    let addr = h2_server(
+        || http::Response::builder().status(200).body(()).unwrap(),
+        b"pinned",
+    )
+    .await;
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Override the server name and pin a made-up host to the server
+    cmd.arg("--sni")
+        .arg("cdn.fff.test")
+        .arg("--resolve")
+        .arg("origin.fff.test:127.0.0.1");
+
+    // Provide the made-up host's URL via stdin
+    let url = format!("https://origin.fff.test:{}/", addr.port());
+    cmd.write_stdin(format!("{}\n", url));
+
+    // Run the command off the runtime the server is on and verify the pin was used
+    let assert = tokio::task::spawn_blocking(move || cmd.assert())
+        .await
+        .unwrap();
+    assert
+        .success()
+        .stdout(predicate::str::contains(format!("{} 200", url)));
+}
+
+#[tokio::test]
+async fn test_accept_invalid_hostnames() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    // Serve HTTPS with a certificate issued by the test CA for another host
+    let cert =
+        CertificateDer::from_pem_slice(include_bytes!("fixtures/wrong-host-cert.pem")).unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("fixtures/wrong-host-key.pem")).unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(vec![cert], key)
+    .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(mut tls) = acceptor.accept(stream).await {
+                    let mut buf = [0u8; 8192];
+                    let _ = tls.read(&mut buf).await;
+                    let _ = tls
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await;
+                    let _ = tls.shutdown().await;
+                }
+            });
+        }
+    });
+
+    let ca = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/test-cert.pem");
+    let url = format!("https://{}/", addr);
+
+    // Trusting the CA, the chain verifies and the wrong name is let through
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+    cmd.arg("--accept-invalid-hostnames")
+        .arg("--cacert")
+        .arg(&ca);
+    cmd.write_stdin(format!("{}\n", url));
+    let assert = tokio::task::spawn_blocking(move || cmd.assert())
+        .await
+        .unwrap();
+    assert
+        .success()
+        .stdout(predicate::str::contains(format!("{} 200", url)));
+
+    // Without the CA the chain is still checked, so the request is rejected
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+    cmd.arg("--accept-invalid-hostnames");
+    cmd.write_stdin(format!("{}\n", url));
+    let assert = tokio::task::spawn_blocking(move || cmd.assert())
+        .await
+        .unwrap();
+    assert
+        .stdout(predicate::str::contains("200").not())
+        .stderr(predicate::str::contains(url.as_str()));
+}
+
+#[tokio::test]
+async fn test_max_errors() {
+    // Find a port with nothing listening on it
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Give up after three failures, one request at a time
+    cmd.arg("--max-errors")
+        .arg("3")
+        .arg("-c")
+        .arg("1")
+        .arg("-d")
+        .arg("0");
+
+    // Provide twenty dead URLs via stdin
+    let urls: String = (0..20)
+        .map(|i| format!("http://{}/{}\n", addr, i))
+        .collect();
+    cmd.write_stdin(urls);
+
+    // Run the command and verify it stopped on the fourth failure
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Too many errors: stopped after 4 failed requests (--max-errors 3)",
+        ))
+        .stderr(predicate::str::contains("Connection failed").count(4));
+}
+
+#[tokio::test]
+async fn test_host_case_directories() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/case");
+        then.status(200).body("case");
+    });
+
+    // Two case variants of the same host
+    let port = server.address().port();
+    let urls = format!(
+        "http://LocalHost:{0}/case\nhttp://localhost:{0}/case\n",
+        port
+    );
+
+    // Save each run's responses in its own directory, listing the host directories
+    let run = |extra: &[&str]| {
+        let temp_dir = TempDir::new().unwrap();
+
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Save all responses
+        cmd.arg("-o").arg(temp_dir.path()).arg("-S").args(extra);
+
+        // Provide the URLs via stdin
+        cmd.write_stdin(urls.clone());
+
+        // Run the command and verify it succeeds
+        cmd.assert().success();
+
+        let mut hosts: Vec<String> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        hosts.sort();
+        hosts
+    };
+
+    // Verify that by default both responses share one lowercase directory
+    assert_eq!(run(&[]), vec!["localhost".to_string()]);
+
+    // Verify that --preserve-host-case keeps the input's spelling apart
+    assert_eq!(
+        run(&["--preserve-host-case"]),
+        vec!["LocalHost".to_string(), "localhost".to_string()]
+    );
+
+    mock.assert_hits(4);
+}
+
+#[tokio::test]
+async fn test_https_upgrade() {
+    // Start a plain HTTP mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/login");
+        then.status(200).body("login");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Probe the https variant as well
+    cmd.arg("--https-upgrade");
+
+    // Provide an http URL via stdin
+    let http_url = server.url("/login");
+    let https_url = http_url.replacen("http://", "https://", 1);
+    cmd.write_stdin(format!("{}\n", http_url));
+
+    // Run the command and verify both schemes were tried; the mock server
+    // doesn't speak TLS, so the https variant fails
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(format!("{} 200 [http]", http_url)))
+        .stderr(predicate::str::contains(format!("for {}", https_url)));
+
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_replay_from_manifest() {
+    // Start a mock server expecting one exact request
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(PUT)
+            .path("/items/1")
+            .header("x-token", "abc")
+            .body("name=fff");
+        then.status(204);
+    });
+
+    // Record the requests to a temporary manifest
+    let temp_dir = TempDir::new().unwrap();
+    let manifest = temp_dir.path().join("requests.jsonl");
+
+    {
+        // Prepare the command
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+
+        // Send a PUT with a header and body, recording it
+        cmd.arg("-m")
+            .arg("PUT")
+            .arg("-H")
+            .arg("X-Token: abc")
+            .arg("-b")
+            .arg("name=fff")
+            .arg("--manifest")
+            .arg(&manifest);
+
+        // Provide the URL via stdin
+        cmd.write_stdin(format!("{}\n", server.url("/items/1")));
+
+        // Run the command and verify it succeeds
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("204"));
+    }
+
+    {
+        // Prepare a command replaying the manifest, with no request options
+        let mut cmd = Command::cargo_bin("fff").unwrap();
+        cmd.arg("--from-manifest").arg(&manifest);
+
+        // Run the command and verify the request was sent again
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(format!(
+                "{} 204",
+                server.url("/items/1")
+            )));
+    }
+
+    // Verify that both runs made the same request
+    mock.assert_hits(2);
+}
+
+#[tokio::test]
+async fn test_invalid_manifest_line() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/");
+        then.status(200);
+    });
+
+    // Write a manifest with a broken line before a valid one
+    let temp_dir = TempDir::new().unwrap();
+    let manifest = temp_dir.path().join("requests.jsonl");
+    fs::write(
+        &manifest,
+        format!(
+            "not json\n{}\n",
+            serde_json::json!({ "method": "GET", "url": server.url("/"), "headers": [] })
+        ),
+    )
+    .unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Replay the manifest with JSON output
+    cmd.arg("--from-manifest").arg(&manifest).arg("--json");
+
+    // Run the command and verify the broken line was reported as JSON
+    let output = cmd.assert().success().get_output().clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let failure: serde_json::Value = serde_json::from_str(stderr.lines().next().unwrap()).unwrap();
+    assert_eq!(failure["kind"], "invalid_manifest_line");
+    assert_eq!(failure["url"], "line 1");
+
+    // Verify that the valid line was still replayed
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_jsonl_full() {
+    use base64::Engine;
+
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    server.mock(|when, then| {
+        when.method(GET).path("/page");
+        then.status(200)
+            .header("X-Powered-By", "fff")
+            .body("<html>inline body</html>");
+    });
+
+    // Create a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Keep every response, but inline it on the JSON line
+    cmd.arg("-o")
+        .arg(temp_dir.path())
+        .arg("-S")
+        .arg("--jsonl-full");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("{}\n", server.url("/page")));
+
+    // Run the command and check it succeeded
+    let output = cmd.assert().success().get_output().clone();
+
+    // Verify that the line carries the headers and a body that decodes to the original
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let result: serde_json::Value = serde_json::from_str(stdout.trim()).unwrap();
+    assert_eq!(result["outcome"], "inline");
+    assert_eq!(result["headers"]["x-powered-by"][0], "fff");
+    let body = base64::engine::general_purpose::STANDARD
+        .decode(result["body_base64"].as_str().unwrap())
+        .unwrap();
+    assert_eq!(body, b"<html>inline body</html>");
+
+    // Verify that nothing was written to disk
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_retry_connection_reset() {
+    // Start a server that hangs up on the first request and answers the second
+    let (addr, requests) = scripted_server(vec![
+        "",
+        "HTTP/1.1 200 OK\r\nContent-Length: 9\r\nConnection: close\r\n\r\nrecovered",
+    ]);
+
+    // Use a temporary output directory
+    let temp_dir = TempDir::new().unwrap();
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Save everything, retrying dropped connections
+    cmd.arg("-o")
+        .arg(temp_dir.path())
+        .arg("-S")
+        .arg("--retry-connection-reset");
+
+    // Provide the URL via stdin
+    cmd.write_stdin(format!("http://{}/flaky\n", addr));
+
+    // Run the command and verify the response was eventually saved
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Saved"));
+
+    // Verify that the request was sent twice and the body saved
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    let expected_dir = temp_dir.path().join("127.0.0.1").join("flaky");
+    let bodies: Vec<String> = fs::read_dir(&expected_dir)
+        .expect("Expected directory not found")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("body"))
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect();
+    assert_eq!(bodies, vec!["recovered"]);
+}
+
+#[tokio::test]
+async fn test_dedupe_path() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/a");
+        then.status(200).body("endpoint");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Collapse URLs that only differ in their query string
+    cmd.arg("-d").arg("0").arg("--dedupe-path");
+
+    // Provide the same endpoint with two different queries
+    cmd.write_stdin(format!(
+        "{}\n{}\n",
+        server.url("/a?x=1"),
+        server.url("/a?x=2")
+    ));
+
+    // Run the command and verify only the first URL was requested
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(server.url("/a?x=1")))
+        .stdout(predicate::str::contains(server.url("/a?x=2")).not());
+
+    // Verify that the endpoint was requested once
+    mock.assert_hits(1);
+}
+
+#[tokio::test]
+async fn test_dedupe_path_relative() {
+    // Start a mock server
+    let server = MockServer::start_async().await;
+
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/a");
+        then.status(200).body("endpoint");
+    });
+
+    // Prepare the command
+    let mut cmd = Command::cargo_bin("fff").unwrap();
+
+    // Collapse relative URLs that only differ in their query string
+    cmd.arg("-d")
+        .arg("0")
+        .arg("--dedupe-path")
+        .arg("--base")
+        .arg(server.url("/"));
+
+    // Provide the same endpoint with two different queries
+    cmd.write_stdin("/a?x=1\n/a?x=2\n");
+
+    // Run the command and verify it succeeds
+    cmd.assert().success();
+
+    // Verify that the endpoint was requested once
+    mock.assert_hits(1);
+}